@@ -5,6 +5,7 @@ use config;
 #[cfg(feature = "cpal-backend")]
 use cpaldevice;
 use filedevice;
+use nulldevice;
 use num_integer as integer;
 #[cfg(feature = "pulse-backend")]
 use pulsedevice;
@@ -62,6 +63,7 @@ pub enum AudioMessage {
 }
 
 /// Main container of audio data
+#[derive(Clone)]
 pub struct AudioChunk {
     pub frames: usize,
     pub channels: usize,
@@ -237,6 +239,17 @@ pub fn get_playback_device(conf: config::Devices) -> Box<dyn PlaybackDevice> {
             channels,
             sample_format: format,
         }),
+        config::PlaybackDevice::Wav {
+            channels,
+            filename,
+            format,
+        } => Box::new(filedevice::FilePlaybackDevice {
+            destination: filedevice::PlaybackDest::Wav(filename),
+            samplerate: conf.samplerate,
+            chunksize: conf.chunksize,
+            channels,
+            sample_format: format,
+        }),
         config::PlaybackDevice::Stdout {
             channels, format, ..
         } => Box::new(filedevice::FilePlaybackDevice {
@@ -246,6 +259,18 @@ pub fn get_playback_device(conf: config::Devices) -> Box<dyn PlaybackDevice> {
             channels,
             sample_format: format,
         }),
+        config::PlaybackDevice::Null {
+            channels,
+            unthrottled,
+        } => Box::new(nulldevice::NullPlaybackDevice {
+            samplerate: conf.samplerate,
+            chunksize: conf.chunksize,
+            channels,
+            unthrottled,
+            target_level: conf.target_level,
+            adjust_period: conf.adjust_period,
+            enable_rate_adjust: conf.enable_rate_adjust,
+        }),
         #[cfg(all(feature = "cpal-backend", target_os = "macos"))]
         config::PlaybackDevice::CoreAudio {
             channels,
@@ -516,6 +541,36 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             stop_on_rate_change: conf.stop_on_rate_change,
             rate_measure_interval: conf.rate_measure_interval,
         }),
+        config::CaptureDevice::Wav {
+            filename,
+            extra_samples,
+            params,
+        } => {
+            // the header was read and checked when validating the config
+            let params = params.unwrap();
+            let wav_samplerate = if conf.enable_resampling {
+                params.sample_rate
+            } else {
+                conf.samplerate
+            };
+            Box::new(filedevice::FileCaptureDevice {
+                source: filedevice::CaptureSource::Filename(filename),
+                samplerate: conf.samplerate,
+                enable_resampling: conf.enable_resampling,
+                capture_samplerate: wav_samplerate,
+                resampler_conf: conf.resampler_type,
+                chunksize: conf.chunksize,
+                channels: params.channels,
+                sample_format: params.sample_format.to_sample_format().unwrap(),
+                extra_samples,
+                silence_threshold: conf.silence_threshold,
+                silence_timeout: conf.silence_timeout,
+                skip_bytes: params.data_offset,
+                read_bytes: params.data_length,
+                stop_on_rate_change: conf.stop_on_rate_change,
+                rate_measure_interval: conf.rate_measure_interval,
+            })
+        }
         config::CaptureDevice::Stdin {
             channels,
             format,
@@ -539,6 +594,30 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             stop_on_rate_change: conf.stop_on_rate_change,
             rate_measure_interval: conf.rate_measure_interval,
         }),
+        config::CaptureDevice::SignalGenerator {
+            channels,
+            signal,
+            active_channels,
+        } => Box::new(filedevice::FileCaptureDevice {
+            source: filedevice::CaptureSource::SignalGenerator {
+                signal,
+                active_channels,
+            },
+            samplerate: conf.samplerate,
+            enable_resampling: conf.enable_resampling,
+            capture_samplerate,
+            resampler_conf: conf.resampler_type,
+            chunksize: conf.chunksize,
+            channels,
+            sample_format: config::SampleFormat::FLOAT64LE,
+            extra_samples: 0,
+            silence_threshold: conf.silence_threshold,
+            silence_timeout: conf.silence_timeout,
+            skip_bytes: 0,
+            read_bytes: 0,
+            stop_on_rate_change: conf.stop_on_rate_change,
+            rate_measure_interval: conf.rate_measure_interval,
+        }),
         #[cfg(all(feature = "cpal-backend", target_os = "macos"))]
         config::CaptureDevice::CoreAudio {
             channels,
@@ -34,19 +34,29 @@ pub struct Volume {
     ramp_step: usize,
     samplerate: usize,
     chunksize: usize,
+    fader: String,
+    min_volume: f32,
+    max_volume: f32,
     processing_status: Arc<RwLock<ProcessingParameters>>,
 }
 
 impl Volume {
+    /// Create a Volume filter following the named fader, starting at the current setting of the fader.
     pub fn new(
         name: String,
         ramp_time_ms: f32,
-        current_volume: f32,
-        mute: bool,
+        fader: String,
         chunksize: usize,
         samplerate: usize,
         processing_status: Arc<RwLock<ProcessingParameters>>,
     ) -> Self {
+        let current = {
+            let mut params = processing_status.write().unwrap();
+            params.add_fader(&fader);
+            params.get_fader(&fader).unwrap_or_default()
+        };
+        let current_volume = current.volume;
+        let mute = current.mute;
         let ramptime_in_chunks =
             (ramp_time_ms / (1000.0 * chunksize as f32 / samplerate as f32)).round() as usize;
         let tempgain: PrcFmt = 10.0;
@@ -62,6 +72,9 @@ impl Volume {
             ramp_step: 0,
             samplerate,
             chunksize,
+            fader,
+            min_volume: f32::NEG_INFINITY,
+            max_volume: f32::INFINITY,
             processing_status,
         }
     }
@@ -73,17 +86,35 @@ impl Volume {
         samplerate: usize,
         processing_status: Arc<RwLock<ProcessingParameters>>,
     ) -> Self {
-        let current_volume = processing_status.read().unwrap().volume;
-        let mute = processing_status.read().unwrap().mute;
-        Volume::new(
+        let mut volume = Volume::new(
             name,
             conf.ramp_time,
-            current_volume,
-            mute,
+            conf.fader,
             chunksize,
             samplerate,
             processing_status,
-        )
+        );
+        volume.set_limits(conf.min_volume, conf.max_volume);
+        volume.clamp_to_limits();
+        volume
+    }
+
+    /// Limit the volume to the range from `min_volume` to `max_volume`.
+    /// If the current volume is outside the new range, it ramps to the nearest limit like a volume change.
+    pub fn set_limits(&mut self, min_volume: f32, max_volume: f32) {
+        self.min_volume = min_volume;
+        self.max_volume = max_volume;
+    }
+
+    /// Move a starting volume that is outside the range directly to the nearest limit, without ramping.
+    fn clamp_to_limits(&mut self) {
+        let volume = self.target_volume.max(self.min_volume).min(self.max_volume);
+        if volume != self.target_volume {
+            self.target_volume = volume;
+            self.current_volume = volume as PrcFmt;
+            self.ramp_start = volume as PrcFmt;
+            self.target_linear_gain = PrcFmt::new(10.0).powf(volume as PrcFmt / 20.0);
+        }
     }
 
     fn make_ramp(&self) -> Vec<PrcFmt> {
@@ -115,8 +146,14 @@ impl Filter for Volume {
     }
 
     fn process_waveform(&mut self, waveform: &mut Vec<PrcFmt>) -> Res<()> {
-        let shared_vol = self.processing_status.read().unwrap().volume;
-        let shared_mute = self.processing_status.read().unwrap().mute;
+        let fader = self
+            .processing_status
+            .read()
+            .unwrap()
+            .get_fader(&self.fader)
+            .unwrap_or_default();
+        let shared_vol = fader.volume.max(self.min_volume).min(self.max_volume);
+        let shared_mute = fader.mute;
 
         // Volume setting changed
         if (shared_vol - self.target_volume).abs() > 0.01 || self.mute != shared_mute {
@@ -181,6 +218,13 @@ impl Filter for Volume {
             self.ramptime_in_chunks = (conf.ramp_time
                 / (1000.0 * self.chunksize as f32 / self.samplerate as f32))
                 .round() as usize;
+            // A change of fader is handled like a volume change, by ramping to the new setting
+            self.processing_status
+                .write()
+                .unwrap()
+                .add_fader(&conf.fader);
+            self.fader = conf.fader;
+            self.set_limits(conf.min_volume, conf.max_volume);
         } else {
             // This should never happen unless there is a bug somewhere else
             panic!("Invalid config change!");
@@ -347,6 +391,45 @@ pub fn validate_delay_config(conf: &config::DelayParameters) -> Res<()> {
 pub fn validate_volume_config(conf: &config::VolumeParameters) -> Res<()> {
     if conf.ramp_time < 0.0 {
         return Err(config::ConfigError::new("Ramp time cannot be negative").into());
+    } else if conf.fader.is_empty() {
+        return Err(config::ConfigError::new("Fader name cannot be empty").into());
+    } else if conf.min_volume >= conf.max_volume {
+        return Err(config::ConfigError::new("Min volume must be less than max volume").into());
+    }
+    Ok(())
+}
+
+/// Get the volume limits of a fader, as the narrowest range allowed by the Volume filters using it.
+/// Returns None if no Volume filter uses the fader.
+pub fn volume_limits(conf: &config::Configuration, fader: &str) -> Option<(f32, f32)> {
+    conf.filters
+        .values()
+        .filter_map(|filter| match filter {
+            config::Filter::Volume { parameters } if parameters.fader == fader => {
+                Some((parameters.min_volume, parameters.max_volume))
+            }
+            _ => None,
+        })
+        .fold(None, |limits, (min, max)| match limits {
+            Some((lim_min, lim_max)) => Some((f32::max(lim_min, min), f32::min(lim_max, max))),
+            None => Some((min, max)),
+        })
+}
+
+/// Validate that the Volume filters using each fader have overlapping volume ranges.
+pub fn validate_fader_limits(conf: &config::Configuration) -> Res<()> {
+    for filter in conf.filters.values() {
+        if let config::Filter::Volume { parameters } = filter {
+            if let Some((min, max)) = volume_limits(conf, &parameters.fader) {
+                if min > max {
+                    let msg = format!(
+                        "The Volume filters using fader '{}' have no common volume range",
+                        parameters.fader
+                    );
+                    return Err(config::ConfigError::new(&msg).into());
+                }
+            }
+        }
     }
     Ok(())
 }
@@ -363,8 +446,13 @@ pub fn validate_gain_config(conf: &config::GainParameters) -> Res<()> {
 
 #[cfg(test)]
 mod tests {
-    use basicfilters::{Delay, Gain};
+    use basicfilters;
+    use basicfilters::{validate_fader_limits, Delay, Gain, Volume};
+    use config;
     use filters::Filter;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use ProcessingParameters;
 
     fn is_close(left: f64, right: f64, maxdiff: f64) -> bool {
         println!("{} - {}", left, right);
@@ -438,4 +526,151 @@ mod tests {
         delay.process_waveform(&mut waveform).unwrap();
         assert!(compare_waveforms(waveform, waveform_delayed, 1.0e-6));
     }
+
+    #[test]
+    fn volume_named_faders() {
+        let params = Arc::new(RwLock::new(ProcessingParameters {
+            volume: -6.0,
+            mute: false,
+            faders: HashMap::new(),
+        }));
+        let mut main = Volume::new(
+            "main".to_string(),
+            0.0,
+            "Main".to_string(),
+            4,
+            48000,
+            params.clone(),
+        );
+        let mut sub = Volume::new(
+            "sub".to_string(),
+            0.0,
+            "Sub".to_string(),
+            4,
+            48000,
+            params.clone(),
+        );
+        // the sub fader is added when the filter is created, and starts at 0 dB
+        assert!(params.write().unwrap().set_fader_volume("Sub", -20.0));
+        assert!(!params.write().unwrap().set_fader_volume("Center", -20.0));
+        let mut waveform = vec![1.0; 4];
+        main.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![0.501187; 4], 1.0e-6));
+        let mut waveform = vec![1.0; 4];
+        sub.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![0.1; 4], 1.0e-6));
+        // muting the sub fader leaves the main fader untouched
+        assert!(params.write().unwrap().set_fader_mute("Sub", true));
+        let mut waveform = vec![1.0; 4];
+        sub.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![0.0; 4], 1.0e-6));
+        let mut waveform = vec![1.0; 4];
+        main.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![0.501187; 4], 1.0e-6));
+    }
+
+    #[test]
+    fn volume_limits() {
+        let params = Arc::new(RwLock::new(ProcessingParameters {
+            volume: 10.0,
+            mute: false,
+            faders: HashMap::new(),
+        }));
+        let mut vol = Volume::new(
+            "vol".to_string(),
+            0.0,
+            "Main".to_string(),
+            4,
+            48000,
+            params.clone(),
+        );
+        vol.set_limits(-40.0, -20.0);
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![0.1; 4], 1.0e-6));
+        params.write().unwrap().volume = -100.0;
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![0.01; 4], 1.0e-6));
+    }
+
+    #[test]
+    fn update_volume_limits() {
+        let params = Arc::new(RwLock::new(ProcessingParameters {
+            volume: 0.0,
+            mute: false,
+            faders: HashMap::new(),
+        }));
+        let conf = config::VolumeParameters {
+            ramp_time: 0.0,
+            fader: "Main".to_string(),
+            min_volume: -150.0,
+            max_volume: 50.0,
+        };
+        let mut vol = Volume::from_config("vol".to_string(), conf.clone(), 4, 48000, params);
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![1.0; 4], 1.0e-6));
+        let new_conf = config::VolumeParameters {
+            max_volume: -20.0,
+            ..conf.clone()
+        };
+        vol.update_parameters(config::Filter::Volume {
+            parameters: new_conf,
+        });
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![0.1; 4], 1.0e-6));
+        let new_conf = config::VolumeParameters {
+            min_volume: -10.0,
+            max_volume: 50.0,
+            ..conf
+        };
+        vol.update_parameters(config::Filter::Volume {
+            parameters: new_conf,
+        });
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![1.0; 4], 1.0e-6));
+    }
+
+    #[test]
+    fn disjoint_fader_limits() {
+        let yaml = r#"
+devices:
+  samplerate: 48000
+  chunksize: 1024
+  capture:
+    type: Stdin
+    channels: 1
+    format: FLOAT64LE
+  playback:
+    type: Stdout
+    channels: 1
+    format: FLOAT64LE
+filters:
+  vol1:
+    type: Volume
+    parameters:
+      fader: Aux1
+      min_volume: -60
+      max_volume: -30
+  vol2:
+    type: Volume
+    parameters:
+      fader: Aux1
+      min_volume: -40
+      max_volume: 0
+"#;
+        let mut conf: config::Configuration = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            basicfilters::volume_limits(&conf, "Aux1"),
+            Some((-40.0, -30.0))
+        );
+        assert!(validate_fader_limits(&conf).is_ok());
+        if let Some(config::Filter::Volume { parameters }) = conf.filters.get_mut("vol2") {
+            parameters.min_volume = -20.0;
+        }
+        assert!(validate_fader_limits(&conf).is_err());
+    }
 }
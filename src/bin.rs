@@ -31,6 +31,7 @@ extern crate slog_scope;
 use slog::Drain;
 
 use clap::{crate_authors, crate_description, crate_version, App, AppSettings, Arg};
+use std::collections::HashMap;
 use std::env;
 use std::fs::OpenOptions;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
@@ -43,22 +44,41 @@ use camillalib::Res;
 
 use camillalib::audiodevice;
 use camillalib::config;
+use camillalib::latency;
+use camillalib::loudnessmeter::LoudnessLevels;
+use camillalib::metrics;
 use camillalib::processing;
+use camillalib::response;
 #[cfg(feature = "websocket")]
 use camillalib::socketserver;
-#[cfg(feature = "websocket")]
+use camillalib::spectrum::Spectrum;
+use camillalib::statefile;
 use std::net::IpAddr;
 
 use camillalib::{
-    list_supported_devices, CaptureStatus, CommandMessage, ExitRequest, ExitState, PlaybackStatus,
-    ProcessingParameters, ProcessingState, ProcessingStatus, StatusMessage, StatusStructs,
-    StopReason,
+    list_supported_devices, CaptureStatus, CommandMessage, ExitRequest, ExitState, PipelineUpdates,
+    PlaybackStatus, ProcessingLoad, ProcessingParameters, ProcessingState, ProcessingStatus,
+    StatusMessage, StatusStructs, StopReason,
 };
 
 const EXIT_BAD_CONFIG: i32 = 101; // Error in config file
 const EXIT_PROCESSING_ERROR: i32 = 102; // Error from processing
 const EXIT_OK: i32 = 0; // All ok
 
+/// Clear the new config after it has been applied,
+/// unless it was already replaced by an even newer config from the websocket.
+fn clear_new_config(
+    new_config_shared: &Arc<Mutex<Option<config::Configuration>>>,
+    applied: &config::Configuration,
+) {
+    let mut new_config = new_config_shared.lock().unwrap();
+    if new_config.as_ref() == Some(applied) {
+        *new_config = None;
+    } else if new_config.is_some() {
+        debug!("Config was replaced while applying it, keeping the newer config");
+    }
+}
+
 fn get_new_config(
     config_path: &Arc<Mutex<Option<String>>>,
     new_config_shared: &Arc<Mutex<Option<config::Configuration>>>,
@@ -105,6 +125,7 @@ fn get_new_config(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run(
     signal_reload: Arc<AtomicBool>,
     signal_exit: Arc<AtomicUsize>,
@@ -112,6 +133,7 @@ fn run(
     config_path: Arc<Mutex<Option<String>>>,
     new_config_shared: Arc<Mutex<Option<config::Configuration>>>,
     prev_config_shared: Arc<Mutex<Option<config::Configuration>>>,
+    pipeline_updates: PipelineUpdates,
     status_structs: StatusStructs,
 ) -> Res<ExitState> {
     status_structs.capture.write().unwrap().state = ProcessingState::Starting;
@@ -132,6 +154,7 @@ fn run(
 
     let (tx_command_cap, rx_command_cap) = mpsc::channel();
     let (tx_pipeconf, rx_pipeconf) = mpsc::channel();
+    *pipeline_updates.lock().unwrap() = Some(tx_pipeconf.clone());
 
     let barrier = Arc::new(Barrier::new(4));
     let barrier_pb = barrier.clone();
@@ -143,6 +166,7 @@ fn run(
     let conf_proc = conf.clone();
 
     let mut active_config = conf;
+    info!("Latency: {}", latency::calculate_latency(&active_config));
     //let conf_yaml = serde_yaml::to_string(&active_config).unwrap();
     *active_config_shared.lock().unwrap() = Some(active_config.clone());
     *new_config_shared.lock().unwrap() = None;
@@ -156,7 +180,7 @@ fn run(
         tx_pb,
         rx_cap,
         rx_pipeconf,
-        status_structs.processing,
+        status_structs.clone(),
     );
 
     // Playback thread
@@ -201,6 +225,12 @@ fn run(
 
             match new_config {
                 Ok(conf) => {
+                    // Hold the lock until the change is sent, since the websocket server
+                    // may also send changes of single filters to the pipeline.
+                    let mut shared_active = active_config_shared.lock().unwrap();
+                    if let Some(current) = shared_active.as_ref() {
+                        active_config = current.clone();
+                    }
                     let comp = config::config_diff(&active_config, &conf);
                     match comp {
                         config::ConfigChange::Pipeline
@@ -208,14 +238,16 @@ fn run(
                         | config::ConfigChange::FilterParameters { .. } => {
                             tx_pipeconf.send((comp, conf.clone())).unwrap();
                             active_config = conf;
-                            *active_config_shared.lock().unwrap() = Some(active_config.clone());
-                            *new_config_shared.lock().unwrap() = None;
+                            *shared_active = Some(active_config.clone());
+                            drop(shared_active);
+                            clear_new_config(&new_config_shared, &active_config);
                             let used_channels = config::get_used_capture_channels(&active_config);
                             debug!("Using channels {:?}", used_channels);
                             status_structs.capture.write().unwrap().used_channels = used_channels;
                             debug!("Sent changes to pipeline");
                         }
                         config::ConfigChange::Devices => {
+                            drop(shared_active);
                             debug!("Devices changed, restart required.");
                             tx_command_cap.send(CommandMessage::Exit).unwrap();
                             trace!("Wait for pb..");
@@ -227,8 +259,9 @@ fn run(
                             return Ok(ExitState::Restart);
                         }
                         config::ConfigChange::None => {
+                            drop(shared_active);
                             debug!("No changes in config.");
-                            *new_config_shared.lock().unwrap() = None;
+                            clear_new_config(&new_config_shared, &conf);
                         }
                     };
                 }
@@ -354,10 +387,14 @@ fn run(
                 }
                 StatusMessage::PlaybackDone => {
                     info!("Playback finished");
-                    let mut stat = status_structs.status.write().unwrap();
-                    if stat.stop_reason == StopReason::None {
-                        stat.stop_reason = StopReason::Done;
+                    {
+                        let mut stat = status_structs.status.write().unwrap();
+                        if stat.stop_reason == StopReason::None {
+                            stat.stop_reason = StopReason::Done;
+                        }
                     }
+                    debug!("Wait for playback thread to exit..");
+                    pb_handle.join().unwrap();
                     *prev_config_shared.lock().unwrap() = Some(active_config);
                     trace!("All threads stopped, returning");
                     return Ok(ExitState::Restart);
@@ -434,7 +471,7 @@ fn main_process() -> i32 {
                 .help("The configuration file to use")
                 .index(1)
                 //.required(true),
-                .required_unless("wait"),
+                .required_unless_one(&["wait", "statefile"]),
         )
         .arg(
             Arg::with_name("check")
@@ -495,6 +532,44 @@ fn main_process() -> i32 {
                 .long("mute")
                 .display_order(200),
         )
+        .arg(
+            Arg::with_name("statefile")
+                .help("Keep volume, mute and config path in a file, and restore them at startup")
+                .long("statefile")
+                .value_name("statefile")
+                .display_order(200)
+                .takes_value(true)
+                .conflicts_with_all(&["check", "offline"]),
+        )
+        .arg(
+            Arg::with_name("metrics_port")
+                .help("Port for serving metrics in Prometheus format over HTTP")
+                .long("metrics-port")
+                .value_name("metrics_port")
+                .display_order(200)
+                .takes_value(true)
+                .validator(|v: String| -> Result<(), String> {
+                    match v.parse::<u16>() {
+                        Ok(port) if port > 0 => Ok(()),
+                        _ => Err(String::from("Must be an integer between 1 and 65535")),
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("metrics_address")
+                .help("IP address to bind metrics server to")
+                .long("metrics-address")
+                .value_name("metrics_address")
+                .display_order(200)
+                .takes_value(true)
+                .requires("metrics_port")
+                .validator(|val: String| -> Result<(), String> {
+                    if val.parse::<IpAddr>().is_ok() {
+                        return Ok(());
+                    }
+                    Err(String::from("Must be a valid IP address"))
+                }),
+        )
         .arg(
             Arg::with_name("samplerate")
                 .help("Override samplerate in config")
@@ -554,6 +629,39 @@ fn main_process() -> i32 {
                 .possible_value("FLOAT32LE")
                 .possible_value("FLOAT64LE")
                 .help("Override sample format of capture device in config"),
+        )
+        .arg(
+            Arg::with_name("offline")
+                .help("Process a File or Stdin capture to a File or Stdout playback as fast as possible, then exit")
+                .long("offline")
+                .display_order(400)
+                .requires("configfile"),
+        )
+        .arg(
+            Arg::with_name("export_response")
+                .help("Export the impulse and frequency responses of the pipeline to <basename>_inN.wav and <basename>_inN.csv, then exit")
+                .long("export-response")
+                .value_name("basename")
+                .display_order(400)
+                .takes_value(true)
+                .requires("configfile")
+                .conflicts_with_all(&["check", "offline"]),
+        )
+        .arg(
+            Arg::with_name("response_length")
+                .help("Length in samples of the exported impulse response")
+                .long("response-length")
+                .display_order(400)
+                .takes_value(true)
+                .requires("export_response")
+                .validator(|v: String| -> Result<(), String> {
+                    if let Ok(length) = v.parse::<usize>() {
+                        if length > 0 {
+                            return Ok(());
+                        }
+                    }
+                    Err(String::from("Must be an integer > 0"))
+                }),
         );
     #[cfg(feature = "websocket")]
     let clapapp = clapapp
@@ -565,12 +673,10 @@ fn main_process() -> i32 {
                 .display_order(200)
                 .takes_value(true)
                 .validator(|v: String| -> Result<(), String> {
-                    if let Ok(port) = v.parse::<usize>() {
-                        if port > 0 && port < 65535 {
-                            return Ok(());
-                        }
+                    match v.parse::<u16>() {
+                        Ok(port) if port > 0 => Ok(()),
+                        _ => Err(String::from("Must be an integer between 1 and 65535")),
                     }
-                    Err(String::from("Must be an integer between 0 and 65535"))
                 }),
         )
         .arg(
@@ -588,12 +694,22 @@ fn main_process() -> i32 {
                     Err(String::from("Must be a valid IP address"))
                 }),
         )
+        .arg(
+            Arg::with_name("export_dir")
+                .help("Directory for responses exported via websocket, exporting is disabled when not given")
+                .long("export-dir")
+                .value_name("export_dir")
+                .display_order(200)
+                .takes_value(true)
+                .requires("port"),
+        )
         .arg(
             Arg::with_name("wait")
                 .short("w")
                 .long("wait")
                 .help("Wait for config from websocket")
-                .requires("port"),
+                .requires("port")
+                .conflicts_with("offline"),
         );
     #[cfg(feature = "secure-websocket")]
     let clapapp = clapapp
@@ -685,14 +801,35 @@ fn main_process() -> i32 {
     #[cfg(target_os = "windows")]
     wasapi::initialize_mta().unwrap();
 
-    let configname = matches.value_of("configfile").map(|path| path.to_string());
+    let statefilename = matches.value_of("statefile").map(|path| path.to_string());
+    let state = statefilename
+        .as_ref()
+        .and_then(|filename| statefile::load_state(filename));
 
-    let initial_volume = matches
-        .value_of("gain")
-        .map(|s| s.parse::<f32>().unwrap())
-        .unwrap_or(0.0);
+    // A config file given on the command line is used instead of the one in the state file
+    let configname = matches
+        .value_of("configfile")
+        .map(|path| path.to_string())
+        .or_else(|| state.as_ref().and_then(|st| st.config_path.clone()));
 
-    let initial_mute = matches.is_present("mute");
+    // A saved state is used instead of the gain and mute command line parameters
+    let (initial_volume, initial_mute, initial_faders) = match state {
+        Some(st) => {
+            info!(
+                "Restoring volume {} dB, mute {} from state file",
+                st.volume, st.mute
+            );
+            (st.volume, st.mute, st.faders)
+        }
+        None => (
+            matches
+                .value_of("gain")
+                .map(|s| s.parse::<f32>().unwrap())
+                .unwrap_or(0.0),
+            matches.is_present("mute"),
+            HashMap::new(),
+        ),
+    };
 
     config::OVERRIDES.write().unwrap().samplerate = matches
         .value_of("samplerate")
@@ -706,6 +843,7 @@ fn main_process() -> i32 {
     config::OVERRIDES.write().unwrap().sample_format = matches
         .value_of("format")
         .map(|s| config::SampleFormat::from_name(s).unwrap());
+    config::OVERRIDES.write().unwrap().offline = matches.is_present("offline");
 
     debug!("Read config file {:?}", configname);
 
@@ -723,6 +861,27 @@ fn main_process() -> i32 {
         }
     }
 
+    if let Some(basename) = matches.value_of("export_response") {
+        let length = matches
+            .value_of("response_length")
+            .map(|s| s.parse::<usize>().unwrap())
+            .unwrap_or(response::DEFAULT_RESPONSE_LENGTH);
+        let conf = match config::load_validate_config(&configname.unwrap()) {
+            Ok(conf) => conf,
+            Err(err) => {
+                error!("{}", err);
+                return EXIT_BAD_CONFIG;
+            }
+        };
+        return match response::export_response(&conf, basename, length) {
+            Ok(()) => EXIT_OK,
+            Err(err) => {
+                error!("Failed to export response: {}", err);
+                EXIT_PROCESSING_ERROR
+            }
+        };
+    }
+
     let configuration = match &configname {
         Some(path) => match config::load_validate_config(&path.clone()) {
             Ok(conf) => {
@@ -739,6 +898,7 @@ fn main_process() -> i32 {
     };
 
     let wait = matches.is_present("wait");
+    let offline = matches.is_present("offline");
 
     let signal_reload = Arc::new(AtomicBool::new(false));
     let signal_exit = Arc::new(AtomicUsize::new(0));
@@ -751,6 +911,7 @@ fn main_process() -> i32 {
         signal_rms: Vec::new(),
         signal_peak: Vec::new(),
         used_channels: Vec::new(),
+        loudness: LoudnessLevels::default(),
     }));
     let playback_status = Arc::new(RwLock::new(PlaybackStatus {
         buffer_level: 0,
@@ -758,13 +919,19 @@ fn main_process() -> i32 {
         update_interval: 1000,
         signal_rms: Vec::new(),
         signal_peak: Vec::new(),
+        loudness: LoudnessLevels::default(),
     }));
     let processing_status = Arc::new(RwLock::new(ProcessingParameters {
         volume: initial_volume,
         mute: initial_mute,
+        faders: initial_faders,
     }));
     let status = Arc::new(RwLock::new(ProcessingStatus {
         stop_reason: StopReason::None,
+        processing_load: ProcessingLoad::default(),
+        capture_spectrum: Spectrum::default(),
+        playback_spectrum: Spectrum::default(),
+        reset_loudness: false,
     }));
 
     let status_structs = StatusStructs {
@@ -776,9 +943,24 @@ fn main_process() -> i32 {
     let active_config = Arc::new(Mutex::new(None));
     let new_config = Arc::new(Mutex::new(configuration));
     let previous_config = Arc::new(Mutex::new(None));
+    let pipeline_updates = Arc::new(Mutex::new(None));
 
     let active_config_path = Arc::new(Mutex::new(configname));
 
+    if let Some(filename) = statefilename {
+        statefile::start_state_writer(
+            filename,
+            processing_status.clone(),
+            active_config_path.clone(),
+        );
+    }
+
+    if let Some(port_str) = matches.value_of("metrics_port") {
+        let address = matches.value_of("metrics_address").unwrap_or("127.0.0.1");
+        let port = port_str.parse::<usize>().unwrap();
+        metrics::start_metrics_server(address, port, status_structs.clone());
+    }
+
     #[cfg(feature = "websocket")]
     {
         if let Some(port_str) = matches.value_of("port") {
@@ -791,10 +973,12 @@ fn main_process() -> i32 {
                 active_config_path: active_config_path.clone(),
                 new_config: new_config.clone(),
                 previous_config: previous_config.clone(),
+                pipeline_updates: pipeline_updates.clone(),
                 capture_status,
                 playback_status,
                 processing_status,
                 status,
+                export_dir: matches.value_of("export_dir").map(|dir| dir.to_string()),
             };
             let server_params = socketserver::ServerParameters {
                 port: serverport,
@@ -809,6 +993,7 @@ fn main_process() -> i32 {
     }
 
     let delay = time::Duration::from_millis(100);
+    let start_time = time::Instant::now();
     loop {
         debug!("Wait for config");
         while new_config.lock().unwrap().is_none() {
@@ -851,6 +1036,7 @@ fn main_process() -> i32 {
             active_config_path.clone(),
             new_config.clone(),
             previous_config.clone(),
+            pipeline_updates.clone(),
             status_structs.clone(),
         );
         match exitstatus {
@@ -868,6 +1054,18 @@ fn main_process() -> i32 {
             }
             Ok(ExitState::Restart) => {
                 *active_config.lock().unwrap() = None;
+                if offline && new_config.lock().unwrap().is_none() {
+                    let stop_reason = status_structs.status.read().unwrap().stop_reason.clone();
+                    if stop_reason == StopReason::Done {
+                        info!(
+                            "Offline processing finished in {:.3} s",
+                            start_time.elapsed().as_secs_f64()
+                        );
+                        return EXIT_OK;
+                    }
+                    error!("Offline processing stopped, reason: {:?}", stop_reason);
+                    return EXIT_PROCESSING_ERROR;
+                }
                 debug!("Restarting with new config");
             }
         };
@@ -5,6 +5,7 @@
 
 use crate::filters::Filter;
 use config;
+use num_complex::Complex;
 
 // Sample format
 //type SmpFmt = i16;
@@ -54,6 +55,25 @@ impl BiquadCoefficients {
         self.a2.abs() < 1.0 && (self.a1.abs() < (self.a2 + 1.0))
     }
 
+    /// Evaluate the complex transfer function at the given frequencies.
+    pub fn frequency_response(
+        &self,
+        samplerate: usize,
+        frequencies: &[PrcFmt],
+    ) -> Vec<Complex<PrcFmt>> {
+        let pi = std::f64::consts::PI as PrcFmt;
+        frequencies
+            .iter()
+            .map(|f| {
+                let omega = 2.0 * pi * f / (samplerate as PrcFmt);
+                let z_inv = Complex::new(omega.cos(), -omega.sin());
+                let z_inv2 = z_inv * z_inv;
+                (z_inv * self.b1 + z_inv2 * self.b2 + self.b0)
+                    / (z_inv * self.a1 + z_inv2 * self.a2 + 1.0)
+            })
+            .collect()
+    }
+
     /// Create biquad filters from config.
     /// Filter types
     /// - Free: just coefficients
@@ -390,6 +410,11 @@ impl Biquad {
         }
     }
 
+    /// Evaluate the complex transfer function at the given frequencies.
+    pub fn frequency_response(&self, frequencies: &[PrcFmt]) -> Vec<Complex<PrcFmt>> {
+        self.coeffs.frequency_response(self.samplerate, frequencies)
+    }
+
     /// Process a single sample
     fn process_single(&mut self, input: PrcFmt) -> PrcFmt {
         let out = self.s1 + self.coeffs.b0 * input;
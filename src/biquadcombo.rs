@@ -6,6 +6,7 @@
 use crate::filters::Filter;
 use biquad;
 use config;
+use num_complex::Complex;
 
 // Sample format
 //type SmpFmt = i16;
@@ -187,6 +188,21 @@ impl BiquadCombo {
             }
         }
     }
+
+    /// Evaluate the complex transfer function at the given frequencies,
+    /// as the product of the responses of the individual biquads.
+    pub fn frequency_response(&self, frequencies: &[PrcFmt]) -> Vec<Complex<PrcFmt>> {
+        let mut response = vec![Complex::new(1.0, 0.0); frequencies.len()];
+        for filter in self.filters.iter() {
+            for (total, value) in response
+                .iter_mut()
+                .zip(filter.frequency_response(frequencies).iter())
+            {
+                *total *= value;
+            }
+        }
+        response
+    }
 }
 
 impl Filter for BiquadCombo {
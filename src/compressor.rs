@@ -0,0 +1,276 @@
+use audiodevice::AudioChunk;
+use config;
+use filters::Processor;
+use helpers::time_to_coeff;
+
+use NewValue;
+use PrcFmt;
+use Res;
+
+#[derive(Clone, Debug)]
+pub struct Compressor {
+    pub name: String,
+    pub channels: usize,
+    pub monitor_channels: Vec<usize>,
+    pub process_channels: Vec<usize>,
+    pub attack: PrcFmt,
+    pub release: PrcFmt,
+    pub threshold: PrcFmt,
+    pub ratio: PrcFmt,
+    pub knee: PrcFmt,
+    pub makeup_gain: PrcFmt,
+    pub samplerate: usize,
+    pub scratch: Vec<PrcFmt>,
+    pub prev_loudness: PrcFmt,
+}
+
+impl Compressor {
+    /// Creates a Compressor from a config struct
+    pub fn from_config(
+        name: String,
+        config: config::CompressorParameters,
+        samplerate: usize,
+        chunksize: usize,
+    ) -> Self {
+        let channels = config.channels;
+        let monitor_channels = config
+            .monitor_channels
+            .unwrap_or_else(|| (0..channels).collect());
+        let process_channels = config
+            .process_channels
+            .unwrap_or_else(|| (0..channels).collect());
+        let attack = time_to_coeff(config.attack, samplerate);
+        let release = time_to_coeff(config.release, samplerate);
+        debug!(
+            "Creating compressor '{}', monitor channels: {:?}, process channels: {:?}",
+            name, monitor_channels, process_channels
+        );
+        Compressor {
+            name,
+            channels,
+            monitor_channels,
+            process_channels,
+            attack,
+            release,
+            threshold: config.threshold,
+            ratio: config.ratio,
+            knee: config.knee,
+            makeup_gain: config.makeup_gain,
+            samplerate,
+            scratch: vec![0.0; chunksize],
+            prev_loudness: -100.0,
+        }
+    }
+
+    /// Find the largest absolute sample value of the monitored channels, for each frame.
+    fn find_peak_of_monitor_channels(&mut self, input: &AudioChunk) {
+        for val in self.scratch.iter_mut() {
+            *val = 0.0;
+        }
+        for ch in self.monitor_channels.iter() {
+            let waveform = &input.waveforms[*ch];
+            for (peak, value) in self.scratch.iter_mut().zip(waveform.iter()) {
+                let absval = value.abs();
+                if absval > *peak {
+                    *peak = absval;
+                }
+            }
+        }
+    }
+
+    /// Convert the peak values to dB and apply attack and release smoothing.
+    fn estimate_loudness(&mut self) {
+        for val in self.scratch.iter_mut() {
+            let level = 20.0 * (*val + 1.0e-9).log10();
+            let coeff = if level > self.prev_loudness {
+                self.attack
+            } else {
+                self.release
+            };
+            *val = coeff * self.prev_loudness + (1.0 - coeff) * level;
+            self.prev_loudness = *val;
+        }
+    }
+
+    /// Gain reduction in dB for a given level, using a soft knee of width `knee` dB.
+    fn gain_reduction(&self, level: PrcFmt) -> PrcFmt {
+        let overshoot = level - self.threshold;
+        if 2.0 * overshoot <= -self.knee {
+            0.0
+        } else if self.knee > 0.0 && 2.0 * overshoot.abs() <= self.knee {
+            (1.0 / self.ratio - 1.0) * (overshoot + self.knee / 2.0).powi(2) / (2.0 * self.knee)
+        } else {
+            (1.0 / self.ratio - 1.0) * overshoot
+        }
+    }
+
+    /// Convert the smoothed loudness values to linear gains, including makeup gain.
+    fn calculate_linear_gain(&mut self) {
+        for idx in 0..self.scratch.len() {
+            let gain_db = self.gain_reduction(self.scratch[idx]) + self.makeup_gain;
+            self.scratch[idx] = PrcFmt::new(10.0).powf(gain_db / 20.0);
+        }
+    }
+
+    fn apply_gain(&self, input: &mut [PrcFmt]) {
+        for (val, gain) in input.iter_mut().zip(self.scratch.iter()) {
+            *val *= gain;
+        }
+    }
+}
+
+impl Processor for Compressor {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Apply the same gain to all processed channels, driven by the loudest monitored channel.
+    fn process_chunk(&mut self, input: &mut AudioChunk) -> Res<()> {
+        self.find_peak_of_monitor_channels(input);
+        self.estimate_loudness();
+        self.calculate_linear_gain();
+        for ch in self.process_channels.iter() {
+            self.apply_gain(&mut input.waveforms[*ch]);
+        }
+        Ok(())
+    }
+
+    fn update_parameters(&mut self, config: config::Processor) {
+        if let config::Processor::Compressor { parameters: config } = config {
+            let channels = config.channels;
+            self.monitor_channels = config
+                .monitor_channels
+                .unwrap_or_else(|| (0..channels).collect());
+            self.process_channels = config
+                .process_channels
+                .unwrap_or_else(|| (0..channels).collect());
+            self.attack = time_to_coeff(config.attack, self.samplerate);
+            self.release = time_to_coeff(config.release, self.samplerate);
+            self.threshold = config.threshold;
+            self.ratio = config.ratio;
+            self.knee = config.knee;
+            self.makeup_gain = config.makeup_gain;
+            debug!(
+                "Updated compressor '{}', monitor channels: {:?}, process channels: {:?}",
+                self.name, self.monitor_channels, self.process_channels
+            );
+        } else {
+            // This should never happen unless there is a bug somewhere else
+            panic!("Invalid config change!");
+        }
+    }
+}
+
+/// Validate the compressor config, to give a helpful message intead of a panic.
+pub fn validate_compressor(channels: usize, config: &config::CompressorParameters) -> Res<()> {
+    if config.channels != channels {
+        let msg = format!(
+            "Compressor has wrong number of channels. Expected {}, found {}.",
+            channels, config.channels
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    if config.attack < 0.0 {
+        return Err(config::ConfigError::new("Attack time cannot be negative").into());
+    }
+    if config.release < 0.0 {
+        return Err(config::ConfigError::new("Release time cannot be negative").into());
+    }
+    if config.ratio < 1.0 {
+        return Err(config::ConfigError::new("Ratio must be 1 or larger").into());
+    }
+    if config.knee < 0.0 {
+        return Err(config::ConfigError::new("Knee width cannot be negative").into());
+    }
+    let monitor_channels = config.monitor_channels.as_deref().unwrap_or(&[]);
+    let process_channels = config.process_channels.as_deref().unwrap_or(&[]);
+    for ch in monitor_channels.iter().chain(process_channels.iter()) {
+        if *ch >= channels {
+            let msg = format!(
+                "Invalid channel {} to monitor or process, max is {}.",
+                ch,
+                channels - 1
+            );
+            return Err(config::ConfigError::new(&msg).into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use audiodevice::AudioChunk;
+    use compressor::{validate_compressor, Compressor};
+    use config::CompressorParameters;
+    use filters::Processor;
+    use PrcFmt;
+
+    fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    fn make_params(threshold: PrcFmt, ratio: PrcFmt, knee: PrcFmt) -> CompressorParameters {
+        CompressorParameters {
+            channels: 2,
+            monitor_channels: None,
+            process_channels: None,
+            attack: 0.0,
+            release: 0.0,
+            threshold,
+            ratio,
+            knee,
+            makeup_gain: 0.0,
+        }
+    }
+
+    #[test]
+    fn gain_reduction_hard_knee() {
+        let comp =
+            Compressor::from_config("test".to_string(), make_params(-20.0, 4.0, 0.0), 48000, 8);
+        assert!(is_close(comp.gain_reduction(-30.0), 0.0, 1.0e-9));
+        assert!(is_close(comp.gain_reduction(-20.0), 0.0, 1.0e-9));
+        assert!(is_close(comp.gain_reduction(-8.0), -9.0, 1.0e-9));
+    }
+
+    #[test]
+    fn gain_reduction_soft_knee() {
+        let comp =
+            Compressor::from_config("test".to_string(), make_params(-20.0, 4.0, 10.0), 48000, 8);
+        // Below and above the knee it behaves like a hard knee
+        assert!(is_close(comp.gain_reduction(-26.0), 0.0, 1.0e-9));
+        assert!(is_close(comp.gain_reduction(-8.0), -9.0, 1.0e-9));
+        // At the threshold, half the knee width is compressed
+        assert!(is_close(comp.gain_reduction(-20.0), -0.9375, 1.0e-9));
+    }
+
+    #[test]
+    fn linked_channels() {
+        let mut comp =
+            Compressor::from_config("test".to_string(), make_params(-20.0, 4.0, 0.0), 48000, 4);
+        let loud = vec![1.0, -1.0, 1.0, -1.0];
+        let quiet = vec![0.01, -0.01, 0.01, -0.01];
+        let mut chunk = AudioChunk::new(vec![loud, quiet], 1.0, -1.0, 4, 4);
+        comp.process_chunk(&mut chunk).unwrap();
+        // 0 dBFS is 20 dB above threshold, reduced by 15 dB on both channels
+        let expected_gain = (10.0 as PrcFmt).powf(-15.0 / 20.0);
+        for (left, right) in chunk.waveforms[0].iter().zip(chunk.waveforms[1].iter()) {
+            assert!(is_close(left.abs(), expected_gain, 1.0e-6));
+            assert!(is_close(right.abs(), 0.01 * expected_gain, 1.0e-6));
+        }
+    }
+
+    #[test]
+    fn check_config() {
+        let okconf = make_params(-20.0, 4.0, 6.0);
+        assert!(validate_compressor(2, &okconf).is_ok());
+        assert!(validate_compressor(3, &okconf).is_err());
+        let badratio = make_params(-20.0, 0.5, 6.0);
+        assert!(validate_compressor(2, &badratio).is_err());
+        let badknee = make_params(-20.0, 4.0, -1.0);
+        assert!(validate_compressor(2, &badknee).is_err());
+        let mut badchannel = make_params(-20.0, 4.0, 6.0);
+        badchannel.monitor_channels = Some(vec![0, 2]);
+        assert!(validate_compressor(2, &badchannel).is_err());
+    }
+}
@@ -1,7 +1,10 @@
+use basicfilters;
 use filters;
 use mixer;
 use serde::{de, Deserialize, Serialize};
 use serde_with;
+use signalgenerator;
+use spectrum;
 use std::collections::HashMap;
 use std::error;
 use std::fmt;
@@ -13,6 +16,7 @@ use std::sync::RwLock;
 
 //type SmpFmt = i16;
 use PrcFmt;
+use MAIN_FADER;
 type Res<T> = Result<T, Box<dyn error::Error>>;
 
 pub struct Overrides {
@@ -20,6 +24,7 @@ pub struct Overrides {
     pub sample_format: Option<SampleFormat>,
     pub extra_samples: Option<usize>,
     pub channels: Option<usize>,
+    pub offline: bool,
 }
 
 lazy_static! {
@@ -28,6 +33,7 @@ lazy_static! {
         sample_format: None,
         extra_samples: None,
         channels: None,
+        offline: false,
     });
 }
 
@@ -155,6 +161,15 @@ pub enum CaptureDevice {
         #[serde(default)]
         read_bytes: usize,
     },
+    #[serde(alias = "WAV", alias = "wav")]
+    Wav {
+        filename: String,
+        #[serde(default)]
+        extra_samples: usize,
+        /// Header of the wav file, read once when validating the config.
+        #[serde(skip)]
+        params: Option<filters::WavParams>,
+    },
     #[serde(alias = "STDIN", alias = "stdin")]
     Stdin {
         #[serde(deserialize_with = "validate_nonzero_usize")]
@@ -167,6 +182,13 @@ pub enum CaptureDevice {
         #[serde(default)]
         read_bytes: usize,
     },
+    SignalGenerator {
+        #[serde(deserialize_with = "validate_nonzero_usize")]
+        channels: usize,
+        signal: Signal,
+        #[serde(default)]
+        active_channels: Option<Vec<usize>>,
+    },
     #[cfg(all(feature = "cpal-backend", target_os = "macos"))]
     #[serde(alias = "COREAUDIO", alias = "coreaudio")]
     CoreAudio {
@@ -196,6 +218,29 @@ pub enum CaptureDevice {
     },
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum Signal {
+    Sine {
+        freq: PrcFmt,
+        level: PrcFmt,
+    },
+    Sweep {
+        start_freq: PrcFmt,
+        end_freq: PrcFmt,
+        length: PrcFmt,
+        level: PrcFmt,
+    },
+    WhiteNoise {
+        level: PrcFmt,
+    },
+    PinkNoise {
+        level: PrcFmt,
+    },
+    Silence,
+}
+
 impl CaptureDevice {
     pub fn channels(&self) -> usize {
         match self {
@@ -204,7 +249,9 @@ impl CaptureDevice {
             #[cfg(feature = "pulse-backend")]
             CaptureDevice::Pulse { channels, .. } => *channels,
             CaptureDevice::File { channels, .. } => *channels,
+            CaptureDevice::Wav { params, .. } => wav_params(params).channels,
             CaptureDevice::Stdin { channels, .. } => *channels,
+            CaptureDevice::SignalGenerator { channels, .. } => *channels,
             #[cfg(all(feature = "cpal-backend", target_os = "macos"))]
             CaptureDevice::CoreAudio { channels, .. } => *channels,
             #[cfg(target_os = "windows")]
@@ -221,7 +268,12 @@ impl CaptureDevice {
             #[cfg(feature = "pulse-backend")]
             CaptureDevice::Pulse { format, .. } => format.clone(),
             CaptureDevice::File { format, .. } => format.clone(),
+            CaptureDevice::Wav { params, .. } => wav_params(params)
+                .sample_format
+                .to_sample_format()
+                .expect("unsupported wav formats are rejected when validating the config"),
             CaptureDevice::Stdin { format, .. } => format.clone(),
+            CaptureDevice::SignalGenerator { .. } => SampleFormat::FLOAT64LE,
             #[cfg(all(feature = "cpal-backend", target_os = "macos"))]
             CaptureDevice::CoreAudio { format, .. } => format.clone(),
             #[cfg(target_os = "windows")]
@@ -232,6 +284,12 @@ impl CaptureDevice {
     }
 }
 
+fn wav_params(params: &Option<filters::WavParams>) -> &filters::WavParams {
+    params
+        .as_ref()
+        .expect("the wav header is read when validating the config")
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
@@ -259,12 +317,26 @@ pub enum PlaybackDevice {
         filename: String,
         format: SampleFormat,
     },
+    #[serde(alias = "WAV", alias = "wav")]
+    Wav {
+        #[serde(deserialize_with = "validate_nonzero_usize")]
+        channels: usize,
+        filename: String,
+        format: SampleFormat,
+    },
     #[serde(alias = "STDOUT", alias = "stdout")]
     Stdout {
         #[serde(deserialize_with = "validate_nonzero_usize")]
         channels: usize,
         format: SampleFormat,
     },
+    #[serde(alias = "NULL", alias = "null")]
+    Null {
+        #[serde(deserialize_with = "validate_nonzero_usize")]
+        channels: usize,
+        #[serde(default)]
+        unthrottled: bool,
+    },
     #[cfg(all(feature = "cpal-backend", target_os = "macos"))]
     #[serde(alias = "COREAUDIO", alias = "coreaudio")]
     CoreAudio {
@@ -300,7 +372,9 @@ impl PlaybackDevice {
             #[cfg(feature = "pulse-backend")]
             PlaybackDevice::Pulse { channels, .. } => *channels,
             PlaybackDevice::File { channels, .. } => *channels,
+            PlaybackDevice::Wav { channels, .. } => *channels,
             PlaybackDevice::Stdout { channels, .. } => *channels,
+            PlaybackDevice::Null { channels, .. } => *channels,
             #[cfg(all(feature = "cpal-backend", target_os = "macos"))]
             PlaybackDevice::CoreAudio { channels, .. } => *channels,
             #[cfg(target_os = "windows")]
@@ -342,6 +416,12 @@ pub struct Devices {
     pub stop_on_rate_change: bool,
     #[serde(default = "default_measure_interval")]
     pub rate_measure_interval: f32,
+    #[serde(default)]
+    pub multithreaded: bool,
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+    #[serde(default)]
+    pub spectrum: Option<SpectrumParameters>,
 }
 
 fn default_period() -> f32 {
@@ -356,6 +436,49 @@ fn default_measure_interval() -> f32 {
     1.0
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SpectrumParameters {
+    #[serde(default = "default_bands_per_octave")]
+    pub bands_per_octave: usize,
+    #[serde(default = "default_spectrum_min_freq")]
+    pub min_freq: f32,
+    #[serde(default = "default_spectrum_max_freq")]
+    pub max_freq: f32,
+    #[serde(default = "default_spectrum_fft_size")]
+    pub fft_size: usize,
+    #[serde(default = "default_spectrum_averaging_time")]
+    pub averaging_time: f32,
+    #[serde(default = "default_true")]
+    pub capture: bool,
+    #[serde(default = "default_true")]
+    pub playback: bool,
+}
+
+fn default_bands_per_octave() -> usize {
+    3
+}
+
+fn default_spectrum_min_freq() -> f32 {
+    20.0
+}
+
+fn default_spectrum_max_freq() -> f32 {
+    20000.0
+}
+
+fn default_spectrum_fft_size() -> usize {
+    8192
+}
+
+fn default_spectrum_averaging_time() -> f32 {
+    0.3
+}
+
+fn default_true() -> bool {
+    true
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub enum Resampler {
@@ -404,6 +527,8 @@ pub enum Filter {
     Conv {
         #[serde(default)]
         parameters: ConvParameters,
+        #[serde(default)]
+        partitioning: ConvPartitioning,
     },
     Biquad {
         parameters: BiquadParameters,
@@ -429,6 +554,12 @@ pub enum Filter {
     DiffEq {
         parameters: DiffEqParameters,
     },
+    Limiter {
+        parameters: LimiterParameters,
+    },
+    Gate {
+        parameters: GateParameters,
+    },
 }
 
 #[allow(clippy::upper_case_acronyms)]
@@ -445,6 +576,19 @@ pub enum FileFormat {
 }
 
 impl FileFormat {
+    /// Get the matching sample format, not available for TEXT.
+    pub fn to_sample_format(&self) -> Option<SampleFormat> {
+        match self {
+            FileFormat::S16LE => Some(SampleFormat::S16LE),
+            FileFormat::S24LE => Some(SampleFormat::S24LE),
+            FileFormat::S24LE3 => Some(SampleFormat::S24LE3),
+            FileFormat::S32LE => Some(SampleFormat::S32LE),
+            FileFormat::FLOAT32LE => Some(SampleFormat::FLOAT32LE),
+            FileFormat::FLOAT64LE => Some(SampleFormat::FLOAT64LE),
+            FileFormat::TEXT => None,
+        }
+    }
+
     pub fn bits_per_sample(&self) -> usize {
         match self {
             FileFormat::S16LE => 16,
@@ -502,6 +646,19 @@ impl Default for FileFormat {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub enum ConvPartitioning {
+    Uniform,
+    NonUniform,
+}
+
+impl Default for ConvPartitioning {
+    fn default() -> Self {
+        ConvPartitioning::Uniform
+    }
+}
+
 impl Default for ConvParameters {
     fn default() -> Self {
         ConvParameters::Values {
@@ -643,12 +800,20 @@ pub enum BiquadComboParameters {
 pub struct VolumeParameters {
     #[serde(default = "default_ramp_time")]
     pub ramp_time: f32,
+    #[serde(default = "default_fader")]
+    pub fader: String,
+    #[serde(default = "default_min_volume")]
+    pub min_volume: f32,
+    #[serde(default = "default_max_volume")]
+    pub max_volume: f32,
 }
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct LoudnessParameters {
     #[serde(default = "default_ramp_time")]
     pub ramp_time: f32,
+    #[serde(default = "default_fader")]
+    pub fader: String,
     pub reference_level: f32,
     #[serde(default = "default_loudness_boost")]
     pub high_boost: f32,
@@ -660,6 +825,18 @@ fn default_loudness_boost() -> f32 {
     10.0
 }
 
+fn default_fader() -> String {
+    MAIN_FADER.to_string()
+}
+
+fn default_min_volume() -> f32 {
+    -150.0
+}
+
+fn default_max_volume() -> f32 {
+    50.0
+}
+
 fn default_ramp_time() -> f32 {
     200.0
 }
@@ -722,6 +899,44 @@ pub struct DiffEqParameters {
     pub b: Vec<PrcFmt>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LimiterParameters {
+    pub ceiling: PrcFmt,
+    #[serde(default = "default_limiter_lookahead")]
+    pub lookahead: PrcFmt,
+    #[serde(default = "default_limiter_release")]
+    pub release: PrcFmt,
+    #[serde(default)]
+    pub true_peak: bool,
+}
+
+fn default_limiter_lookahead() -> PrcFmt {
+    5.0
+}
+
+fn default_limiter_release() -> PrcFmt {
+    50.0
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GateParameters {
+    pub threshold: PrcFmt,
+    #[serde(default)]
+    pub hysteresis: PrcFmt,
+    pub attack: PrcFmt,
+    #[serde(default)]
+    pub hold: PrcFmt,
+    pub release: PrcFmt,
+    #[serde(default = "default_gate_range")]
+    pub range: PrcFmt,
+}
+
+fn default_gate_range() -> PrcFmt {
+    -80.0
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct MixerChannels {
@@ -757,12 +972,56 @@ pub struct Mixer {
     pub mapping: Vec<MixerMapping>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+#[serde(deny_unknown_fields)]
+pub enum Processor {
+    Compressor { parameters: CompressorParameters },
+    Crossfeed { parameters: CrossfeedParameters },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CompressorParameters {
+    #[serde(deserialize_with = "validate_nonzero_usize")]
+    pub channels: usize,
+    #[serde(default)]
+    pub monitor_channels: Option<Vec<usize>>,
+    #[serde(default)]
+    pub process_channels: Option<Vec<usize>>,
+    pub attack: PrcFmt,
+    pub release: PrcFmt,
+    pub threshold: PrcFmt,
+    pub ratio: PrcFmt,
+    #[serde(default)]
+    pub knee: PrcFmt,
+    #[serde(default)]
+    pub makeup_gain: PrcFmt,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+#[serde(deny_unknown_fields)]
+pub enum CrossfeedParameters {
+    Preset { preset: CrossfeedPreset },
+    Custom { cutoff: PrcFmt, feed_level: PrcFmt },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub enum CrossfeedPreset {
+    Default,
+    CMoy,
+    JMeier,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type")]
 #[serde(deny_unknown_fields)]
 pub enum PipelineStep {
     Mixer { name: String },
     Filter { channel: usize, names: Vec<String> },
+    Processor { name: String },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -775,7 +1034,11 @@ pub struct Configuration {
     #[serde(deserialize_with = "serde_with::rust::maps_duplicate_key_is_error::deserialize")]
     pub filters: HashMap<String, Filter>,
     #[serde(default)]
+    pub processors: HashMap<String, Processor>,
+    #[serde(default)]
     pub pipeline: Vec<PipelineStep>,
+    #[serde(default)]
+    pub crossfade_time: PrcFmt,
 }
 
 fn validate_nonzero_usize<'de, D>(d: D) -> Result<usize, D::Error>
@@ -823,7 +1086,29 @@ pub fn load_config(filename: &str) -> Res<Configuration> {
     Ok(configuration)
 }
 
+/// Check if the devices can run in offline mode, reading and writing as fast as possible.
+fn supports_offline(devices: &Devices) -> bool {
+    let file_capture = matches!(
+        devices.capture,
+        CaptureDevice::File { .. } | CaptureDevice::Wav { .. } | CaptureDevice::Stdin { .. }
+    );
+    let file_playback = matches!(
+        devices.playback,
+        PlaybackDevice::File { .. }
+            | PlaybackDevice::Wav { .. }
+            | PlaybackDevice::Stdout { .. }
+            | PlaybackDevice::Null { .. }
+    );
+    file_capture && file_playback
+}
+
 fn apply_overrides(configuration: &mut Configuration) {
+    if OVERRIDES.read().unwrap().offline {
+        // Pausing on silence would drop audio, and the measured rate is meaningless
+        debug!("Offline mode, disabling silence detection and rate change detection");
+        configuration.devices.silence_timeout = 0.0;
+        configuration.devices.stop_on_rate_change = false;
+    }
     if let Some(rate) = OVERRIDES.read().unwrap().samplerate {
         let cfg_rate = configuration.devices.samplerate;
         let cfg_chunksize = configuration.devices.chunksize;
@@ -848,6 +1133,11 @@ fn apply_overrides(configuration: &mut Configuration) {
                     debug!("Scale extra samples: {} -> {}", *extra_samples, new_extra);
                     *extra_samples = new_extra;
                 }
+                CaptureDevice::Wav { extra_samples, .. } => {
+                    let new_extra = *extra_samples * rate / cfg_rate;
+                    debug!("Scale extra samples: {} -> {}", *extra_samples, new_extra);
+                    *extra_samples = new_extra;
+                }
                 CaptureDevice::Stdin { extra_samples, .. } => {
                     let new_extra = *extra_samples * rate / cfg_rate;
                     debug!("Scale extra samples: {} -> {}", *extra_samples, new_extra);
@@ -871,6 +1161,9 @@ fn apply_overrides(configuration: &mut Configuration) {
             CaptureDevice::File { extra_samples, .. } => {
                 *extra_samples = extra;
             }
+            CaptureDevice::Wav { extra_samples, .. } => {
+                *extra_samples = extra;
+            }
             CaptureDevice::Stdin { extra_samples, .. } => {
                 *extra_samples = extra;
             }
@@ -883,9 +1176,15 @@ fn apply_overrides(configuration: &mut Configuration) {
             CaptureDevice::File { channels, .. } => {
                 *channels = chans;
             }
+            CaptureDevice::Wav { .. } => {
+                error!("Not possible to override capture channels for Wav, ignoring");
+            }
             CaptureDevice::Stdin { channels, .. } => {
                 *channels = chans;
             }
+            CaptureDevice::SignalGenerator { channels, .. } => {
+                *channels = chans;
+            }
             #[cfg(all(feature = "alsa-backend", target_os = "linux"))]
             CaptureDevice::Alsa { channels, .. } => {
                 *channels = chans;
@@ -914,9 +1213,15 @@ fn apply_overrides(configuration: &mut Configuration) {
             CaptureDevice::File { format, .. } => {
                 *format = fmt;
             }
+            CaptureDevice::Wav { .. } => {
+                error!("Not possible to override capture format for Wav, ignoring");
+            }
             CaptureDevice::Stdin { format, .. } => {
                 *format = fmt;
             }
+            CaptureDevice::SignalGenerator { .. } => {
+                error!("Not possible to override capture format for SignalGenerator, ignoring");
+            }
             #[cfg(all(feature = "alsa-backend", target_os = "linux"))]
             CaptureDevice::Alsa { format, .. } => {
                 *format = fmt;
@@ -956,9 +1261,11 @@ fn replace_tokens_in_config(config: &mut Configuration) {
         match filter {
             Filter::Conv {
                 parameters: ConvParameters::Raw { filename, .. },
+                ..
             }
             | Filter::Conv {
                 parameters: ConvParameters::Wav { filename, .. },
+                ..
             } => {
                 *filename = replace_tokens(filename, samplerate, num_channels);
             }
@@ -975,6 +1282,9 @@ fn replace_tokens_in_config(config: &mut Configuration) {
             PipelineStep::Mixer { name } => {
                 *name = replace_tokens(name, samplerate, num_channels);
             }
+            PipelineStep::Processor { name } => {
+                *name = replace_tokens(name, samplerate, num_channels);
+            }
         }
     }
 }
@@ -986,11 +1296,13 @@ fn replace_relative_paths_in_config(config: &mut Configuration, configname: &str
             for (_name, filter) in config.filters.iter_mut() {
                 if let Filter::Conv {
                     parameters: ConvParameters::Raw { filename, .. },
+                    ..
                 } = filter
                 {
                     check_and_replace_relative_path(filename, config_dir);
                 } else if let Filter::Conv {
                     parameters: ConvParameters::Wav { filename, .. },
+                    ..
                 } = filter
                 {
                     check_and_replace_relative_path(filename, config_dir);
@@ -1029,6 +1341,7 @@ pub enum ConfigChange {
     FilterParameters {
         filters: Vec<String>,
         mixers: Vec<String>,
+        processors: Vec<String>,
     },
     MixerParameters,
     Pipeline,
@@ -1052,16 +1365,36 @@ pub fn config_diff(currentconf: &Configuration, newconf: &Configuration) -> Conf
     if currentconf.pipeline != newconf.pipeline {
         return ConfigChange::Pipeline;
     }
-    if currentconf.mixers != newconf.mixers {
-        return ConfigChange::MixerParameters;
+    for (mixer, params) in &newconf.mixers {
+        // A mixer that changed its channels or connections needs a new pipeline,
+        // while changes of gain, mute and inversion can be applied in place
+        if let Some(current_mixer) = currentconf.mixers.get(mixer) {
+            if !mixer::is_same_topology(current_mixer, params) {
+                return ConfigChange::MixerParameters;
+            }
+        }
     }
     let mut filters = Vec::<String>::new();
     let mut mixers = Vec::<String>::new();
+    let mut processors = Vec::<String>::new();
     for (filter, params) in &newconf.filters {
         // The pipeline didn't change, any added filter isn't included and can be skipped
         if let Some(current_filter) = currentconf.filters.get(filter) {
             // Did the filter change type?
             match (params, current_filter) {
+                (
+                    Filter::Conv {
+                        partitioning: new_partitioning,
+                        ..
+                    },
+                    Filter::Conv {
+                        partitioning: current_partitioning,
+                        ..
+                    },
+                ) if new_partitioning != current_partitioning => {
+                    // Switching between convolution engines needs a new filter
+                    return ConfigChange::Pipeline;
+                }
                 (Filter::Biquad { .. }, Filter::Biquad { .. })
                 | (Filter::BiquadCombo { .. }, Filter::BiquadCombo { .. })
                 | (Filter::Conv { .. }, Filter::Conv { .. })
@@ -1070,7 +1403,9 @@ pub fn config_diff(currentconf: &Configuration, newconf: &Configuration) -> Conf
                 | (Filter::Dither { .. }, Filter::Dither { .. })
                 | (Filter::DiffEq { .. }, Filter::DiffEq { .. })
                 | (Filter::Volume { .. }, Filter::Volume { .. })
-                | (Filter::Loudness { .. }, Filter::Loudness { .. }) => {}
+                | (Filter::Loudness { .. }, Filter::Loudness { .. })
+                | (Filter::Limiter { .. }, Filter::Limiter { .. })
+                | (Filter::Gate { .. }, Filter::Gate { .. }) => {}
                 _ => {
                     // A filter changed type, need to rebuild the pipeline
                     return ConfigChange::Pipeline;
@@ -1090,18 +1425,90 @@ pub fn config_diff(currentconf: &Configuration, newconf: &Configuration) -> Conf
             }
         }
     }
-    ConfigChange::FilterParameters { filters, mixers }
+    for (processor, params) in &newconf.processors {
+        // The pipeline didn't change, any added processor isn't included and can be skipped
+        if let Some(current_processor) = currentconf.processors.get(processor) {
+            // Did the processor change type?
+            match (params, current_processor) {
+                (Processor::Compressor { .. }, Processor::Compressor { .. })
+                | (Processor::Crossfeed { .. }, Processor::Crossfeed { .. }) => {}
+                _ => {
+                    // A processor changed type, need to rebuild the pipeline
+                    return ConfigChange::Pipeline;
+                }
+            };
+            if params != current_processor {
+                processors.push(processor.to_string());
+            }
+        }
+    }
+    ConfigChange::FilterParameters {
+        filters,
+        mixers,
+        processors,
+    }
+}
+
+/// Read the header of a Wav capture device and store it in the device,
+/// so that the file is only parsed once.
+fn read_capture_wav_header(devices: &mut Devices) -> Res<()> {
+    if let CaptureDevice::Wav {
+        filename, params, ..
+    } = &mut devices.capture
+    {
+        let header = filters::find_data_in_wav(filename)?;
+        if header.sample_format.to_sample_format().is_none() {
+            let msg = format!("Unsupported sample format of wav file '{}'", filename);
+            return Err(ConfigError::new(&msg).into());
+        }
+        *params = Some(header);
+    }
+    Ok(())
 }
 
 /// Validate the loaded configuration, stop on errors and print a helpful message.
 pub fn validate_config(conf: &mut Configuration, filename: Option<&str>) -> Res<()> {
     // pre-process by applying overrides and replacing tokens
     apply_overrides(conf);
+    read_capture_wav_header(&mut conf.devices)?;
     replace_tokens_in_config(conf);
     if let Some(fname) = filename {
         replace_relative_paths_in_config(conf, fname);
     }
 
+    if OVERRIDES.read().unwrap().offline && !supports_offline(&conf.devices) {
+        return Err(ConfigError::new(
+                "Offline mode requires a File, Wav or Stdin capture device, and a File, Wav, Stdout or Null playback device",
+            )
+            .into());
+    }
+    if let CaptureDevice::Wav {
+        filename, params, ..
+    } = &conf.devices.capture
+    {
+        let params = wav_params(params);
+        if !conf.devices.enable_resampling && params.sample_rate != conf.devices.samplerate {
+            let msg = format!(
+                "Wav file '{}' has a sample rate of {} Hz, enable resampling to use it at {} Hz",
+                filename, params.sample_rate, conf.devices.samplerate
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+    }
+    if let CaptureDevice::SignalGenerator {
+        signal,
+        channels,
+        active_channels,
+    } = &conf.devices.capture
+    {
+        let capture_samplerate =
+            if conf.devices.capture_samplerate > 0 && conf.devices.enable_resampling {
+                conf.devices.capture_samplerate
+            } else {
+                conf.devices.samplerate
+            };
+        signalgenerator::validate_config(signal, *channels, active_channels, capture_samplerate)?;
+    }
     if conf.devices.target_level >= 2 * conf.devices.chunksize {
         let msg = format!(
             "target_level can't be larger than {}",
@@ -1118,6 +1525,15 @@ pub fn validate_config(conf: &mut Configuration, filename: Option<&str>) -> Res<
     if conf.devices.silence_timeout < 0.0 {
         return Err(ConfigError::new("silence_timeout cannot be negative").into());
     }
+    if conf.crossfade_time < 0.0 {
+        return Err(ConfigError::new("crossfade_time cannot be negative").into());
+    }
+    if let Some(spectrum) = &conf.devices.spectrum {
+        spectrum::validate_config(spectrum, conf.devices.samplerate)?;
+    }
+    if conf.devices.worker_threads == Some(0) {
+        return Err(ConfigError::new("worker_threads must be at least 1").into());
+    }
     #[cfg(all(feature = "cpal-backend", target_os = "macos"))]
     if let CaptureDevice::CoreAudio { format, .. } = &conf.devices.capture {
         if !(*format == SampleFormat::FLOAT32LE || *format == SampleFormat::S16LE) {
@@ -1256,8 +1672,24 @@ pub fn validate_config(conf: &mut Configuration, filename: Option<&str>) -> Res<
                     }
                 }
             }
+            PipelineStep::Processor { name } => {
+                if !conf.processors.contains_key(name) {
+                    let msg = format!("Use of missing processor '{}'", name);
+                    return Err(ConfigError::new(&msg).into());
+                } else {
+                    let procconf = conf.processors.get(name).unwrap();
+                    match filters::validate_processor(num_channels, procconf) {
+                        Ok(_) => {}
+                        Err(err) => {
+                            let msg = format!("Invalid processor '{}'. Reason: {}", name, err);
+                            return Err(ConfigError::new(&msg).into());
+                        }
+                    }
+                }
+            }
         }
     }
+    basicfilters::validate_fader_limits(conf)?;
     let num_channels_out = conf.devices.playback.channels();
     if num_channels != num_channels_out {
         let msg = format!(
@@ -1280,3 +1712,43 @@ pub fn get_used_capture_channels(conf: &Configuration) -> Vec<bool> {
     let capture_channels = conf.devices.capture.channels();
     vec![true; capture_channels]
 }
+
+#[cfg(test)]
+mod tests {
+    use config::{read_capture_wav_header, supports_offline, Devices, SampleFormat};
+    use std::fs;
+
+    fn devices(capture: &str, playback: &str) -> Devices {
+        let yaml = format!(
+            "samplerate: 44100\nchunksize: 1024\ncapture:\n{}playback:\n{}",
+            capture, playback
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn offline_devices() {
+        let wav = "  type: Wav\n  filename: in.wav\n";
+        let generator = "  type: SignalGenerator\n  channels: 2\n  signal:\n    type: Silence\n";
+        let file = "  type: File\n  filename: out.raw\n  channels: 2\n  format: S16LE\n";
+        let null = "  type: Null\n  channels: 2\n";
+        assert!(supports_offline(&devices(wav, file)));
+        assert!(supports_offline(&devices(wav, null)));
+        assert!(!supports_offline(&devices(generator, null)));
+    }
+
+    #[test]
+    fn wav_header_read_once() {
+        let path = std::env::temp_dir().join("camilla_test_capture.wav");
+        fs::copy("testdata/int32.wav", &path).unwrap();
+        let wav = format!("  type: Wav\n  filename: {}\n", path.to_str().unwrap());
+        let mut devs = devices(&wav, "  type: Null\n  channels: 1\n");
+        read_capture_wav_header(&mut devs).unwrap();
+        // the stored header is used even after the file is gone
+        fs::remove_file(&path).unwrap();
+        assert_eq!(devs.capture.channels(), 1);
+        assert_eq!(devs.capture.sampleformat(), SampleFormat::S32LE);
+        let mut missing = devices(&wav, "  type: Null\n  channels: 1\n");
+        assert!(read_capture_wav_header(&mut missing).is_err());
+    }
+}
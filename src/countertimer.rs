@@ -5,6 +5,8 @@ use ProcessingState;
 
 pub struct Averager {
     sum: f64,
+    min: f64,
+    max: f64,
     nbr_values: usize,
 }
 
@@ -106,20 +108,42 @@ impl Averager {
     pub fn new() -> Averager {
         Averager {
             sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
             nbr_values: 0,
         }
     }
 
     pub fn restart(&mut self) {
         self.sum = 0.0;
+        self.min = f64::INFINITY;
+        self.max = f64::NEG_INFINITY;
         self.nbr_values = 0;
     }
 
     pub fn add_value(&mut self, value: f64) {
         self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
         self.nbr_values += 1;
     }
 
+    pub fn get_min(&self) -> Option<f64> {
+        if self.nbr_values > 0 {
+            Some(self.min)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_max(&self) -> Option<f64> {
+        if self.nbr_values > 0 {
+            Some(self.max)
+        } else {
+            None
+        }
+    }
+
     pub fn get_average(&self) -> Option<f64> {
         if self.nbr_values > 0 {
             Some(self.sum / (self.nbr_values as f64))
@@ -245,8 +269,11 @@ mod tests {
         a.add_value(2.0);
         a.add_value(6.0);
         assert_eq!(a.get_average(), Some(3.0));
+        assert_eq!(a.get_min(), Some(1.0));
+        assert_eq!(a.get_max(), Some(6.0));
         a.restart();
         assert_eq!(a.get_average(), None);
+        assert_eq!(a.get_max(), None);
     }
 
     #[test]
@@ -0,0 +1,217 @@
+use audiodevice::AudioChunk;
+use config;
+use filters::Processor;
+
+use NewValue;
+use PrcFmt;
+use Res;
+
+/// Headphone crossfeed, based on the Bauer stereophonic-to-binaural (bs2b) filter.
+/// Each output channel is the sum of a high-boosted copy of the same channel,
+/// and a lowpass filtered copy of the opposite channel.
+/// The crossfeed is applied to channels 0 (left) and 1 (right).
+#[derive(Clone, Debug)]
+pub struct Crossfeed {
+    pub name: String,
+    pub samplerate: usize,
+    a0_lo: PrcFmt,
+    b1_lo: PrcFmt,
+    a0_hi: PrcFmt,
+    a1_hi: PrcFmt,
+    b1_hi: PrcFmt,
+    gain: PrcFmt,
+    // filter states, [left, right]
+    prev_in: [PrcFmt; 2],
+    prev_lo: [PrcFmt; 2],
+    prev_hi: [PrcFmt; 2],
+}
+
+/// Get the cutoff frequency in Hz and feed level in dB for a crossfeed config.
+fn cutoff_and_level(config: &config::CrossfeedParameters) -> (PrcFmt, PrcFmt) {
+    match config {
+        config::CrossfeedParameters::Preset { preset } => match preset {
+            config::CrossfeedPreset::Default => (700.0, 4.5),
+            config::CrossfeedPreset::CMoy => (700.0, 6.0),
+            config::CrossfeedPreset::JMeier => (650.0, 9.5),
+        },
+        config::CrossfeedParameters::Custom { cutoff, feed_level } => (*cutoff, *feed_level),
+    }
+}
+
+impl Crossfeed {
+    /// Creates a Crossfeed from a config struct
+    pub fn from_config(
+        name: String,
+        config: config::CrossfeedParameters,
+        samplerate: usize,
+    ) -> Self {
+        let mut crossfeed = Crossfeed {
+            name,
+            samplerate,
+            a0_lo: 0.0,
+            b1_lo: 0.0,
+            a0_hi: 0.0,
+            a1_hi: 0.0,
+            b1_hi: 0.0,
+            gain: 0.0,
+            prev_in: [0.0; 2],
+            prev_lo: [0.0; 2],
+            prev_hi: [0.0; 2],
+        };
+        crossfeed.set_parameters(&config);
+        crossfeed
+    }
+
+    /// Calculate the filter coefficients, without touching the filter states.
+    fn set_parameters(&mut self, config: &config::CrossfeedParameters) {
+        let (cutoff, level) = cutoff_and_level(config);
+        let gain_lo_db = level * -5.0 / 6.0 - 3.0;
+        let gain_hi_db = level / 6.0 - 3.0;
+        let gain_lo = PrcFmt::new(10.0).powf(gain_lo_db / 20.0);
+        let gain_hi = 1.0 - PrcFmt::new(10.0).powf(gain_hi_db / 20.0);
+        let cutoff_hi =
+            cutoff * PrcFmt::new(2.0).powf((gain_lo_db - 20.0 * gain_hi.log10()) / 12.0);
+
+        let x =
+            (-2.0 * (std::f64::consts::PI as PrcFmt) * cutoff / (self.samplerate as PrcFmt)).exp();
+        self.b1_lo = x;
+        self.a0_lo = gain_lo * (1.0 - x);
+
+        let x = (-2.0 * (std::f64::consts::PI as PrcFmt) * cutoff_hi / (self.samplerate as PrcFmt))
+            .exp();
+        self.b1_hi = x;
+        self.a0_hi = 1.0 - gain_hi * (1.0 - x);
+        self.a1_hi = -x;
+
+        // normalize to unity gain at DC for a mono signal
+        self.gain = 1.0 / (1.0 - gain_hi + gain_lo);
+        debug!(
+            "Crossfeed '{}', cutoff: {} Hz, feed level: {} dB",
+            self.name, cutoff, level
+        );
+    }
+}
+
+impl Processor for Crossfeed {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn process_chunk(&mut self, input: &mut AudioChunk) -> Res<()> {
+        let (left, rest) = input.waveforms.split_at_mut(1);
+        let left = &mut left[0];
+        let right = &mut rest[0];
+        for (val_l, val_r) in left.iter_mut().zip(right.iter_mut()) {
+            let lo_l = self.a0_lo * *val_l + self.b1_lo * self.prev_lo[0];
+            let lo_r = self.a0_lo * *val_r + self.b1_lo * self.prev_lo[1];
+            let hi_l =
+                self.a0_hi * *val_l + self.a1_hi * self.prev_in[0] + self.b1_hi * self.prev_hi[0];
+            let hi_r =
+                self.a0_hi * *val_r + self.a1_hi * self.prev_in[1] + self.b1_hi * self.prev_hi[1];
+            self.prev_in = [*val_l, *val_r];
+            self.prev_lo = [lo_l, lo_r];
+            self.prev_hi = [hi_l, hi_r];
+            *val_l = (hi_l + lo_r) * self.gain;
+            *val_r = (hi_r + lo_l) * self.gain;
+        }
+        Ok(())
+    }
+
+    fn update_parameters(&mut self, config: config::Processor) {
+        if let config::Processor::Crossfeed { parameters: config } = config {
+            self.set_parameters(&config);
+        } else {
+            // This should never happen unless there is a bug somewhere else
+            panic!("Invalid config change!");
+        }
+    }
+}
+
+/// Validate the crossfeed config, to give a helpful message intead of a panic.
+pub fn validate_crossfeed(channels: usize, config: &config::CrossfeedParameters) -> Res<()> {
+    if channels < 2 {
+        let msg = format!(
+            "Crossfeed needs at least 2 channels, the pipeline has {}.",
+            channels
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    if let config::CrossfeedParameters::Custom { cutoff, feed_level } = config {
+        if *cutoff < 300.0 || *cutoff > 2000.0 {
+            return Err(config::ConfigError::new(
+                "Crossfeed cutoff must be in the range 300 to 2000 Hz",
+            )
+            .into());
+        }
+        if *feed_level < 1.0 || *feed_level > 15.0 {
+            return Err(config::ConfigError::new(
+                "Crossfeed feed level must be in the range 1 to 15 dB",
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use audiodevice::AudioChunk;
+    use config::{CrossfeedParameters, CrossfeedPreset};
+    use crossfeed::{validate_crossfeed, Crossfeed};
+    use filters::Processor;
+    use PrcFmt;
+
+    fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    fn process_dc(crossfeed: &mut Crossfeed, left: PrcFmt, right: PrcFmt) -> (PrcFmt, PrcFmt) {
+        let mut chunk = AudioChunk::new(
+            vec![vec![left; 4410], vec![right; 4410]],
+            1.0,
+            0.0,
+            4410,
+            4410,
+        );
+        crossfeed.process_chunk(&mut chunk).unwrap();
+        (chunk.waveforms[0][4409], chunk.waveforms[1][4409])
+    }
+
+    #[test]
+    fn mono_dc_is_unchanged() {
+        let conf = CrossfeedParameters::Preset {
+            preset: CrossfeedPreset::Default,
+        };
+        let mut crossfeed = Crossfeed::from_config("test".to_string(), conf, 44100);
+        let (left, right) = process_dc(&mut crossfeed, 0.5, 0.5);
+        assert!(is_close(left, 0.5, 1.0e-6));
+        assert!(is_close(right, 0.5, 1.0e-6));
+    }
+
+    #[test]
+    fn feed_level_at_dc() {
+        let conf = CrossfeedParameters::Custom {
+            cutoff: 700.0,
+            feed_level: 6.0,
+        };
+        let mut crossfeed = Crossfeed::from_config("test".to_string(), conf, 44100);
+        let (left, right) = process_dc(&mut crossfeed, 1.0, 0.0);
+        assert!(is_close(20.0 * (right / left).log10(), -6.0, 1.0e-3));
+    }
+
+    #[test]
+    fn check_config() {
+        let conf = CrossfeedParameters::Custom {
+            cutoff: 700.0,
+            feed_level: 6.0,
+        };
+        assert!(validate_crossfeed(2, &conf).is_ok());
+        assert!(validate_crossfeed(1, &conf).is_err());
+        let conf = CrossfeedParameters::Custom {
+            cutoff: 100.0,
+            feed_level: 6.0,
+        };
+        assert!(validate_crossfeed(2, &conf).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::filters::Filter;
 use config;
+use num_complex::Complex;
 
 // Sample format
 //type SmpFmt = i16;
@@ -49,6 +50,31 @@ impl DiffEq {
         DiffEq::new(name, a, b)
     }
 
+    /// Evaluate the complex transfer function at the given frequencies.
+    /// Like when processing, the a0 coefficient is assumed to be 1.0.
+    pub fn frequency_response(
+        &self,
+        samplerate: usize,
+        frequencies: &[PrcFmt],
+    ) -> Vec<Complex<PrcFmt>> {
+        let pi = std::f64::consts::PI as PrcFmt;
+        frequencies
+            .iter()
+            .map(|f| {
+                let omega = 2.0 * pi * f / (samplerate as PrcFmt);
+                let z_inv = Complex::new(omega.cos(), -omega.sin());
+                let polynomial = |coeffs: &[PrcFmt]| {
+                    let mut sum = Complex::new(0.0, 0.0);
+                    for coeff in coeffs.iter().rev() {
+                        sum = sum * z_inv + coeff;
+                    }
+                    sum
+                };
+                polynomial(&self.b) / (polynomial(&self.a) - self.a[0] + 1.0)
+            })
+            .collect()
+    }
+
     /// Process a single sample
     fn process_single(&mut self, input: PrcFmt) -> PrcFmt {
         let mut out = 0.0;
@@ -128,4 +154,13 @@ mod tests {
         filter.process_waveform(&mut wave).unwrap();
         assert!(compare_waveforms(wave, expected, 1e-3));
     }
+
+    #[test]
+    fn check_frequency_response() {
+        // two point moving average, unity gain at DC and zero at Nyquist
+        let filter = DiffEq::new("test".to_string(), vec![], vec![0.5, 0.5]);
+        let resp = filter.frequency_response(48000, &[0.0, 24000.0]);
+        assert!(is_close(resp[0].norm(), 1.0, 1e-9));
+        assert!(is_close(resp[1].norm(), 0.0, 1e-9));
+    }
 }
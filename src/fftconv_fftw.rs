@@ -145,21 +145,7 @@ impl FftConv {
     }
 
     pub fn from_config(name: String, data_length: usize, conf: config::ConvParameters) -> Self {
-        let values = match conf {
-            config::ConvParameters::Values { values, length } => {
-                filters::pad_vector(&values, length)
-            }
-            config::ConvParameters::Raw {
-                filename,
-                format,
-                read_bytes_lines,
-                skip_bytes_lines,
-            } => filters::read_coeff_file(&filename, &format, read_bytes_lines, skip_bytes_lines)
-                .unwrap(),
-            config::ConvParameters::Wav { filename, channel } => {
-                filters::read_wav(&filename, channel).unwrap()
-            }
-        };
+        let values = filters::read_conv_coefficients(&conf).unwrap();
         FftConv::new(name, data_length, &values)
     }
 }
@@ -210,24 +196,11 @@ impl Filter for FftConv {
     }
 
     fn update_parameters(&mut self, conf: config::Filter) {
-        if let config::Filter::Conv { parameters: conf } = conf {
-            let coeffs = match conf {
-                config::ConvParameters::Values { values, length } => {
-                    filters::pad_vector(&values, length)
-                }
-                config::ConvParameters::Raw {
-                    filename,
-                    format,
-                    read_bytes_lines,
-                    skip_bytes_lines,
-                } => {
-                    filters::read_coeff_file(&filename, &format, read_bytes_lines, skip_bytes_lines)
-                        .unwrap()
-                }
-                config::ConvParameters::Wav { filename, channel } => {
-                    filters::read_wav(&filename, channel).unwrap()
-                }
-            };
+        if let config::Filter::Conv {
+            parameters: conf, ..
+        } = conf
+        {
+            let coeffs = filters::read_conv_coefficients(&conf).unwrap();
 
             let nsegments = ((coeffs.len() as PrcFmt) / (self.npoints as PrcFmt)).ceil() as usize;
 
@@ -5,13 +5,14 @@ use conversions::{buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes};
 use countertimer;
 use std::fs::File;
 use std::io::ErrorKind;
-use std::io::{stdin, stdout, Read, Write};
+use std::io::{stdin, stdout, Read, Seek, SeekFrom, Write};
 use std::sync::mpsc;
 use std::sync::{Arc, Barrier, RwLock};
 use std::thread;
 use std::time::Duration;
 
 use rubato::VecResampler;
+use signalgenerator::SignalGenerator;
 
 use crate::{CaptureStatus, PlaybackStatus};
 use CommandMessage;
@@ -32,14 +33,148 @@ pub struct FilePlaybackDevice {
 pub enum CaptureSource {
     Filename(String),
     Stdin,
+    SignalGenerator {
+        signal: config::Signal,
+        active_channels: Option<Vec<usize>>,
+    },
 }
 
 #[derive(Clone)]
 pub enum PlaybackDest {
     Filename(String),
+    Wav(String),
     Stdout,
 }
 
+/// Write a wav header for the given format and data length in bytes.
+/// 24 bit samples in 4 byte containers use the extensible format,
+/// all other formats use the basic 16 byte fmt chunk.
+/// Returns the length of the header, which is also the offset of the data.
+pub fn write_wav_header(
+    file: &mut dyn Write,
+    channels: usize,
+    samplerate: usize,
+    sample_format: &SampleFormat,
+    data_length: u32,
+) -> std::io::Result<u64> {
+    let bytes_per_sample = sample_format.bytes_per_sample();
+    let extensible = *sample_format == SampleFormat::S24LE;
+    let fmt_length: u32 = if extensible { 40 } else { 16 };
+    let formatcode: u16 = match sample_format {
+        _ if extensible => 0xFFFE,
+        SampleFormat::FLOAT32LE | SampleFormat::FLOAT64LE => 3,
+        _ => 1,
+    };
+    file.write_all(b"RIFF")?;
+    file.write_all(&(20 + fmt_length).saturating_add(data_length).to_le_bytes())?;
+    file.write_all(b"WAVE")?;
+    file.write_all(b"fmt ")?;
+    file.write_all(&fmt_length.to_le_bytes())?;
+    file.write_all(&formatcode.to_le_bytes())?;
+    file.write_all(&(channels as u16).to_le_bytes())?;
+    file.write_all(&(samplerate as u32).to_le_bytes())?;
+    file.write_all(&((samplerate * channels * bytes_per_sample) as u32).to_le_bytes())?;
+    file.write_all(&((channels * bytes_per_sample) as u16).to_le_bytes())?;
+    file.write_all(&(8 * bytes_per_sample as u16).to_le_bytes())?;
+    if extensible {
+        // cbSize, valid bits, channel mask, and the KSDATAFORMAT_SUBTYPE_PCM guid
+        file.write_all(&22u16.to_le_bytes())?;
+        file.write_all(&(sample_format.bits_per_sample() as u16).to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&[1, 0, 0, 0, 0, 0, 16, 0, 128, 0, 0, 170, 0, 56, 155, 113])?;
+    }
+    file.write_all(b"data")?;
+    file.write_all(&data_length.to_le_bytes())?;
+    Ok(20 + fmt_length as u64 + 8)
+}
+
+/// A destination for the playback data, that must be finalized when the stream ends.
+pub trait PlaybackWriter: Write {
+    /// Write any remaining data, and complete the file.
+    fn finalize(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+impl PlaybackWriter for File {}
+
+impl PlaybackWriter for std::io::Stdout {}
+
+/// A wav file writer. The header is written when the file is created,
+/// and the chunk sizes in the header are updated by `finalize`,
+/// or when the writer is dropped if it was not finalized.
+pub struct WavWriter {
+    file: File,
+    data_offset: u64,
+    data_length: u64,
+    finalized: bool,
+}
+
+impl WavWriter {
+    pub fn create(
+        filename: &str,
+        channels: usize,
+        samplerate: usize,
+        sample_format: &SampleFormat,
+    ) -> std::io::Result<Self> {
+        let mut file = File::create(filename)?;
+        let data_offset = write_wav_header(&mut file, channels, samplerate, sample_format, 0)?;
+        Ok(WavWriter {
+            file,
+            data_offset,
+            data_length: 0,
+            finalized: false,
+        })
+    }
+
+    /// Write the final RIFF and data chunk sizes, with a pad byte if the data length is odd.
+    fn update_header(&mut self) -> std::io::Result<()> {
+        let mut riff_length = self.data_offset - 8 + self.data_length;
+        if self.data_length % 2 == 1 {
+            self.file.write_all(&[0])?;
+            riff_length += 1;
+        }
+        let riff_length = riff_length.min(u32::MAX as u64) as u32;
+        let data_length = self.data_length.min(u32::MAX as u64) as u32;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&riff_length.to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(self.data_offset - 4))?;
+        self.file.write_all(&data_length.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+impl Write for WavWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.data_length += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl PlaybackWriter for WavWriter {
+    fn finalize(&mut self) -> std::io::Result<()> {
+        if !self.finalized {
+            self.finalized = true;
+            self.update_header()?;
+            debug!("Wrote {} bytes of wav data", self.data_length);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.finalize() {
+            error!("Unable to update wav header: {}", err);
+        }
+    }
+}
+
 pub struct FileCaptureDevice {
     pub source: CaptureSource,
     pub chunksize: usize,
@@ -93,15 +228,20 @@ impl PlaybackDevice for FilePlaybackDevice {
     ) -> Res<Box<thread::JoinHandle<()>>> {
         let destination = self.destination.clone();
         let chunksize = self.chunksize;
+        let samplerate = self.samplerate;
         let channels = self.channels;
         let store_bytes_per_sample = self.sample_format.bytes_per_sample();
         let sample_format = self.sample_format.clone();
         let handle = thread::Builder::new()
             .name("FilePlayback".to_string())
             .spawn(move || {
-                let file_res: Result<Box<dyn Write>, std::io::Error> = match destination {
+                let file_res: Result<Box<dyn PlaybackWriter>, std::io::Error> = match destination {
                     PlaybackDest::Filename(filename) => {
-                        File::create(filename).map(|f| Box::new(f) as Box<dyn Write>)
+                        File::create(filename).map(|f| Box::new(f) as Box<dyn PlaybackWriter>)
+                    }
+                    PlaybackDest::Wav(filename) => {
+                        WavWriter::create(&filename, channels, samplerate, &sample_format)
+                            .map(|f| Box::new(f) as Box<dyn PlaybackWriter>)
                     }
                     PlaybackDest::Stdout => Ok(Box::new(stdout())),
                 };
@@ -148,6 +288,12 @@ impl PlaybackDevice for FilePlaybackDevice {
                                     );
                                 }
                                 Ok(AudioMessage::EndOfStream) => {
+                                    if let Err(err) = file.finalize() {
+                                        error!("Unable to finalize playback file: {}", err);
+                                        status_channel
+                                            .send(StatusMessage::PlaybackError(err.to_string()))
+                                            .unwrap_or(());
+                                    }
                                     status_channel
                                         .send(StatusMessage::PlaybackDone)
                                         .unwrap_or(());
@@ -491,6 +637,15 @@ impl CaptureDevice for FileCaptureDevice {
                         File::open(filename).map(|f| Box::new(f) as Box<dyn Read>)
                     }
                     CaptureSource::Stdin => Ok(Box::new(stdin())),
+                    CaptureSource::SignalGenerator {
+                        signal,
+                        active_channels,
+                    } => Ok(Box::new(SignalGenerator::new(
+                        signal,
+                        channels,
+                        active_channels,
+                        capture_samplerate,
+                    ))),
                 };
                 match file_res {
                     Ok(mut file) => {
@@ -579,3 +734,90 @@ fn sleep_until_next(bytes_per_frame: usize, samplerate: usize, nbr_bytes: usize)
         thread::sleep(io_duration - Duration::from_millis(2));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use audiodevice::{AudioChunk, AudioMessage, PlaybackDevice};
+    use config::{FileFormat, SampleFormat};
+    use filedevice::{FilePlaybackDevice, PlaybackDest, WavWriter};
+    use filters::find_data_in_wav;
+    use loudnessmeter::LoudnessLevels;
+    use std::io::Write;
+    use std::sync::{mpsc, Arc, Barrier, RwLock};
+    use {PlaybackStatus, StatusMessage};
+
+    fn write_and_analyze(name: &str, sample_format: SampleFormat, data: &[u8]) {
+        let path = std::env::temp_dir().join(name);
+        let filename = path.to_str().unwrap();
+        {
+            let mut writer = WavWriter::create(filename, 2, 44100, &sample_format).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        let info = find_data_in_wav(filename).unwrap();
+        println!("{:?}", info);
+        assert_eq!(info.sample_format.to_sample_format(), Some(sample_format));
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.data_length, data.len());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_wav_headers() {
+        write_and_analyze("camilla_test_s16.wav", SampleFormat::S16LE, &[0; 8]);
+        write_and_analyze("camilla_test_s24.wav", SampleFormat::S24LE, &[0; 16]);
+        write_and_analyze("camilla_test_f64.wav", SampleFormat::FLOAT64LE, &[0; 32]);
+        assert_eq!(
+            FileFormat::S24LE3.to_sample_format(),
+            Some(SampleFormat::S24LE3)
+        );
+    }
+
+    #[test]
+    fn wav_header_done_before_playback_done() {
+        let path = std::env::temp_dir().join("camilla_test_playback.wav");
+        let filename = path.to_str().unwrap().to_string();
+        let mut device = FilePlaybackDevice {
+            destination: PlaybackDest::Wav(filename.clone()),
+            chunksize: 16,
+            samplerate: 44100,
+            channels: 2,
+            sample_format: SampleFormat::S16LE,
+        };
+        let (tx_audio, rx_audio) = mpsc::sync_channel(4);
+        let (tx_status, rx_status) = mpsc::channel();
+        let barrier = Arc::new(Barrier::new(2));
+        let playback_status = Arc::new(RwLock::new(PlaybackStatus {
+            buffer_level: 0,
+            clipped_samples: 0,
+            update_interval: 1000,
+            signal_rms: Vec::new(),
+            signal_peak: Vec::new(),
+            loudness: LoudnessLevels::default(),
+        }));
+        let handle = device
+            .start(rx_audio, barrier.clone(), tx_status, playback_status)
+            .unwrap();
+        barrier.wait();
+        for _ in 0..3 {
+            let chunk = AudioChunk::new(vec![vec![0.1; 16], vec![-0.1; 16]], 0.1, -0.1, 16, 16);
+            tx_audio.send(AudioMessage::Audio(chunk)).unwrap();
+        }
+        tx_audio.send(AudioMessage::EndOfStream).unwrap();
+        loop {
+            match rx_status.recv().unwrap() {
+                StatusMessage::PlaybackDone => break,
+                StatusMessage::PlaybackReady => {}
+                _ => panic!("unexpected status message"),
+            }
+        }
+        // the header must be complete as soon as PlaybackDone is received
+        let info = find_data_in_wav(&filename).unwrap();
+        assert_eq!(info.data_length, 3 * 16 * 2 * 2);
+        let data = std::fs::read(&path).unwrap();
+        let riff_length = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        assert_eq!(riff_length as usize, data.len() - 8);
+        handle.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -2,23 +2,29 @@ use audiodevice::AudioChunk;
 use basicfilters;
 use biquad;
 use biquadcombo;
+use compressor;
 use config;
 use conversions;
+use crossfeed;
 use diffeq;
 use dither;
 #[cfg(not(feature = "FFTW"))]
 use fftconv;
 #[cfg(feature = "FFTW")]
 use fftconv_fftw as fftconv;
+use gate;
+use limiter;
 use loudness;
 use mixer;
+use nonuniformconv;
 use rawsample::SampleReader;
+use rayon::prelude::*;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::{BufRead, Read, Seek, SeekFrom};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 use PrcFmt;
 use ProcessingParameters;
@@ -65,16 +71,16 @@ const SUBTYPE_PCM: Guid = Guid {
     data4: [128, 0, 0, 170, 0, 56, 155, 113],
 };
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct WavParams {
-    sample_format: config::FileFormat,
-    sample_rate: usize,
-    data_offset: usize,
-    data_length: usize,
-    channels: usize,
+    pub sample_format: config::FileFormat,
+    pub sample_rate: usize,
+    pub data_offset: usize,
+    pub data_length: usize,
+    pub channels: usize,
 }
 
-pub trait Filter {
+pub trait Filter: Send {
     // Filter a Vec
     fn process_waveform(&mut self, waveform: &mut Vec<PrcFmt>) -> Res<()>;
 
@@ -83,6 +89,15 @@ pub trait Filter {
     fn name(&self) -> String;
 }
 
+pub trait Processor {
+    // Process a chunk containing several channels.
+    fn process_chunk(&mut self, chunk: &mut AudioChunk) -> Res<()>;
+
+    fn update_parameters(&mut self, config: config::Processor);
+
+    fn name(&self) -> String;
+}
+
 pub fn pad_vector(values: &[PrcFmt], length: usize) -> Vec<PrcFmt> {
     let new_len = if values.len() > length {
         values.len()
@@ -94,6 +109,20 @@ pub fn pad_vector(values: &[PrcFmt], length: usize) -> Vec<PrcFmt> {
     new_values
 }
 
+/// Load the coefficients of a convolution filter.
+pub fn read_conv_coefficients(conf: &config::ConvParameters) -> Res<Vec<PrcFmt>> {
+    match conf {
+        config::ConvParameters::Values { values, length } => Ok(pad_vector(values, *length)),
+        config::ConvParameters::Raw {
+            filename,
+            format,
+            read_bytes_lines,
+            skip_bytes_lines,
+        } => read_coeff_file(filename, format, *read_bytes_lines, *skip_bytes_lines),
+        config::ConvParameters::Wav { filename, channel } => read_wav(filename, *channel),
+    }
+}
+
 pub fn read_coeff_file(
     filename: &str,
     format: &config::FileFormat,
@@ -257,6 +286,7 @@ pub fn find_data_in_wav(filename: &str) -> Res<WavParams> {
                         (SUBTYPE_PCM, 16, 2, 16) => config::FileFormat::S16LE,
                         (SUBTYPE_PCM, 24, 3, 24) => config::FileFormat::S24LE3,
                         (SUBTYPE_PCM, 24, 4, 24) => config::FileFormat::S24LE,
+                        (SUBTYPE_PCM, 32, 4, 24) => config::FileFormat::S24LE,
                         (SUBTYPE_PCM, 32, 4, 32) => config::FileFormat::S32LE,
                         (SUBTYPE_FLOAT, 32, 4, 32) => config::FileFormat::FLOAT32LE,
                         (SUBTYPE_FLOAT, 64, 8, 64) => config::FileFormat::FLOAT64LE,
@@ -358,7 +388,18 @@ impl FilterGroup {
             let filter_cfg = filter_configs[&name].clone();
             let filter: Box<dyn Filter> =
                 match filter_cfg {
-                    config::Filter::Conv { parameters } => Box::new(fftconv::FftConv::from_config(
+                    config::Filter::Conv {
+                        parameters,
+                        partitioning: config::ConvPartitioning::Uniform,
+                    } => Box::new(fftconv::FftConv::from_config(
+                        name,
+                        waveform_length,
+                        parameters,
+                    )),
+                    config::Filter::Conv {
+                        parameters,
+                        partitioning: config::ConvPartitioning::NonUniform,
+                    } => Box::new(nonuniformconv::NonUniformConv::from_config(
                         name,
                         waveform_length,
                         parameters,
@@ -401,6 +442,12 @@ impl FilterGroup {
                     config::Filter::DiffEq { parameters } => {
                         Box::new(diffeq::DiffEq::from_config(name, parameters))
                     }
+                    config::Filter::Limiter { parameters } => {
+                        Box::new(limiter::Limiter::from_config(name, sample_freq, parameters))
+                    }
+                    config::Filter::Gate { parameters } => {
+                        Box::new(gate::Gate::from_config(name, sample_freq, parameters))
+                    }
                 };
             filters.push(filter);
         }
@@ -421,9 +468,14 @@ impl FilterGroup {
 
     /// Apply all the filters to an AudioChunk.
     fn process_chunk(&mut self, input: &mut AudioChunk) -> Res<()> {
-        if !input.waveforms[self.channel].is_empty() {
+        self.process_waveform(&mut input.waveforms[self.channel])
+    }
+
+    /// Apply all the filters to the waveform of the channel of this group.
+    fn process_waveform(&mut self, waveform: &mut Vec<PrcFmt>) -> Res<()> {
+        if !waveform.is_empty() {
             for filter in &mut self.filters {
-                filter.process_waveform(&mut input.waveforms[self.channel])?;
+                filter.process_waveform(waveform)?;
             }
         }
         Ok(())
@@ -431,14 +483,82 @@ impl FilterGroup {
 }
 
 /// A Pipeline is made up of a series of PipelineSteps,
-/// each one can be a single Mixer of a group of Filters
+/// each one can be a single Mixer, a group of Filters or a Processor.
+/// When multithreaded processing is enabled, consecutive filter steps are merged
+/// into a ParallelFilterStep, holding the filter groups of each channel in the original order.
 pub enum PipelineStep {
     MixerStep(mixer::Mixer),
     FilterStep(FilterGroup),
+    ParallelFilterStep(Vec<Vec<FilterGroup>>),
+    ProcessorStep(Box<dyn Processor>),
 }
 
 pub struct Pipeline {
     steps: Vec<PipelineStep>,
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+/// A cached worker pool, together with the requested number of threads.
+type WorkerPoolCache = Mutex<Option<(Option<usize>, Arc<rayon::ThreadPool>)>>;
+
+lazy_static! {
+    /// The worker pool shared by all pipelines.
+    static ref WORKER_POOL: WorkerPoolCache = Mutex::new(None);
+}
+
+/// Get the shared worker pool, creating it on first use.
+fn get_worker_pool(threads: Option<usize>) -> Arc<rayon::ThreadPool> {
+    get_cached_pool(&WORKER_POOL, threads)
+}
+
+/// Get the pool stored in a cache, creating it on first use.
+/// A new pool is only created if the requested number of threads has changed.
+fn get_cached_pool(cache: &WorkerPoolCache, threads: Option<usize>) -> Arc<rayon::ThreadPool> {
+    let mut shared = cache.lock().unwrap();
+    if let Some((pool_threads, pool)) = shared.as_ref() {
+        if *pool_threads == threads {
+            return pool.clone();
+        }
+    }
+    let mut builder =
+        rayon::ThreadPoolBuilder::new().thread_name(|idx| format!("FilterWorker{}", idx));
+    if let Some(threads) = threads {
+        builder = builder.num_threads(threads);
+    }
+    let pool = Arc::new(builder.build().unwrap());
+    debug!(
+        "Created worker pool with {} threads",
+        pool.current_num_threads()
+    );
+    *shared = Some((threads, pool.clone()));
+    pool
+}
+
+/// Merge runs of consecutive filter steps, that involve more than one channel, into parallel steps.
+fn merge_filter_steps(steps: Vec<PipelineStep>) -> Vec<PipelineStep> {
+    let mut merged = Vec::<PipelineStep>::new();
+    let mut run = Vec::<FilterGroup>::new();
+    for step in steps.into_iter().map(Some).chain(std::iter::once(None)) {
+        if let Some(PipelineStep::FilterStep(group)) = step {
+            run.push(group);
+            continue;
+        }
+        let first_channel = run.first().map(|group| group.channel);
+        if run.iter().any(|group| Some(group.channel) != first_channel) {
+            let nbr_channels = run.iter().map(|group| group.channel).max().unwrap() + 1;
+            let mut channels = (0..nbr_channels).map(|_| Vec::new()).collect::<Vec<_>>();
+            for group in run.drain(..) {
+                channels[group.channel].push(group);
+            }
+            merged.push(PipelineStep::ParallelFilterStep(channels));
+        } else {
+            merged.extend(run.drain(..).map(PipelineStep::FilterStep));
+        }
+        if let Some(step) = step {
+            merged.push(step);
+        }
+    }
+    merged
 }
 
 impl Pipeline {
@@ -467,9 +587,41 @@ impl Pipeline {
                     );
                     steps.push(PipelineStep::FilterStep(fltgrp));
                 }
+                config::PipelineStep::Processor { name } => {
+                    let procconf = conf.processors[&name].clone();
+                    let proc: Box<dyn Processor> = match procconf {
+                        config::Processor::Compressor { parameters } => {
+                            Box::new(compressor::Compressor::from_config(
+                                name,
+                                parameters,
+                                conf.devices.samplerate,
+                                conf.devices.chunksize,
+                            ))
+                        }
+                        config::Processor::Crossfeed { parameters } => {
+                            Box::new(crossfeed::Crossfeed::from_config(
+                                name,
+                                parameters,
+                                conf.devices.samplerate,
+                            ))
+                        }
+                    };
+                    steps.push(PipelineStep::ProcessorStep(proc));
+                }
             }
         }
-        Pipeline { steps }
+        let pool = if conf.devices.multithreaded {
+            let pool = get_worker_pool(conf.devices.worker_threads);
+            debug!(
+                "Processing filters in parallel, using {} worker threads",
+                pool.current_num_threads()
+            );
+            steps = merge_filter_steps(steps);
+            Some(pool)
+        } else {
+            None
+        };
+        Pipeline { steps, pool }
     }
 
     pub fn update_parameters(
@@ -477,6 +629,7 @@ impl Pipeline {
         conf: config::Configuration,
         filters: Vec<String>,
         mixers: Vec<String>,
+        processors: Vec<String>,
     ) {
         debug!("Updating parameters");
         for mut step in &mut self.steps {
@@ -489,11 +642,21 @@ impl Pipeline {
                 PipelineStep::FilterStep(flt) => {
                     flt.update_parameters(conf.filters.clone(), filters.clone());
                 }
+                PipelineStep::ParallelFilterStep(channels) => {
+                    for flt in channels.iter_mut().flatten() {
+                        flt.update_parameters(conf.filters.clone(), filters.clone());
+                    }
+                }
+                PipelineStep::ProcessorStep(proc) => {
+                    if processors.iter().any(|n| n == &proc.name()) {
+                        proc.update_parameters(conf.processors[&proc.name()].clone());
+                    }
+                }
             }
         }
     }
 
-    /// Process an AudioChunk by calling either a MixerStep or a FilterStep
+    /// Process an AudioChunk by calling either a MixerStep, a FilterStep or a ProcessorStep
     pub fn process_chunk(&mut self, mut chunk: AudioChunk) -> AudioChunk {
         for mut step in &mut self.steps {
             match &mut step {
@@ -503,6 +666,23 @@ impl Pipeline {
                 PipelineStep::FilterStep(flt) => {
                     flt.process_chunk(&mut chunk).unwrap();
                 }
+                PipelineStep::ParallelFilterStep(channels) => {
+                    // Each channel is processed by one worker, applying its filter groups in order.
+                    let waveforms = &mut chunk.waveforms;
+                    self.pool.as_ref().unwrap().install(|| {
+                        waveforms
+                            .par_iter_mut()
+                            .zip(channels.par_iter_mut())
+                            .for_each(|(waveform, groups)| {
+                                for flt in groups.iter_mut() {
+                                    flt.process_waveform(waveform).unwrap();
+                                }
+                            });
+                    });
+                }
+                PipelineStep::ProcessorStep(proc) => {
+                    proc.process_chunk(&mut chunk).unwrap();
+                }
             }
         }
         chunk
@@ -512,7 +692,7 @@ impl Pipeline {
 /// Validate the filter config, to give a helpful message intead of a panic.
 pub fn validate_filter(fs: usize, filter_config: &config::Filter) -> Res<()> {
     match filter_config {
-        config::Filter::Conv { parameters } => fftconv::validate_config(parameters),
+        config::Filter::Conv { parameters, .. } => fftconv::validate_config(parameters),
         config::Filter::Biquad { parameters } => biquad::validate_config(fs, parameters),
         config::Filter::Delay { parameters } => basicfilters::validate_delay_config(parameters),
         config::Filter::Gain { parameters } => basicfilters::validate_gain_config(parameters),
@@ -521,15 +701,33 @@ pub fn validate_filter(fs: usize, filter_config: &config::Filter) -> Res<()> {
         config::Filter::Volume { parameters } => basicfilters::validate_volume_config(parameters),
         config::Filter::Loudness { parameters } => loudness::validate_config(parameters),
         config::Filter::BiquadCombo { parameters } => biquadcombo::validate_config(fs, parameters),
+        config::Filter::Limiter { parameters } => limiter::validate_config(parameters),
+        config::Filter::Gate { parameters } => gate::validate_config(parameters),
+    }
+}
+
+/// Validate the processor config, to give a helpful message intead of a panic.
+pub fn validate_processor(channels: usize, processor_config: &config::Processor) -> Res<()> {
+    match processor_config {
+        config::Processor::Compressor { parameters } => {
+            compressor::validate_compressor(channels, parameters)
+        }
+        config::Processor::Crossfeed { parameters } => {
+            crossfeed::validate_crossfeed(channels, parameters)
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::PrcFmt;
-    use config::FileFormat;
-    use filters::{find_data_in_wav, read_wav};
+    use audiodevice::AudioChunk;
+    use config::{Configuration, FileFormat};
+    use filters::{find_data_in_wav, get_cached_pool, read_wav, Pipeline};
     use filters::{pad_vector, read_coeff_file};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, RwLock};
+    use ProcessingParameters;
 
     fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
         println!("{} - {} = {}", left, right, left - right);
@@ -719,4 +917,85 @@ mod tests {
         let bad = read_wav("testdata/int32.wav", 1);
         assert!(bad.is_err());
     }
+
+    fn process_impulse(conf: &Configuration) -> Vec<Vec<PrcFmt>> {
+        let params = Arc::new(RwLock::new(ProcessingParameters {
+            volume: 0.0,
+            mute: false,
+            faders: HashMap::new(),
+        }));
+        let mut pipeline = Pipeline::from_config(conf.clone(), params);
+        let mut waveforms = vec![vec![0.0; 64]; 3];
+        for (ch, wf) in waveforms.iter_mut().enumerate() {
+            wf[ch] = 1.0;
+        }
+        let chunk = AudioChunk::new(waveforms, 1.0, 0.0, 64, 64);
+        pipeline.process_chunk(chunk).waveforms
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let yaml = r#"
+devices:
+  samplerate: 48000
+  chunksize: 64
+  capture:
+    type: Stdin
+    channels: 3
+    format: FLOAT64LE
+  playback:
+    type: Stdout
+    channels: 3
+    format: FLOAT64LE
+filters:
+  lowpass:
+    type: Biquad
+    parameters:
+      type: Lowpass
+      freq: 1000
+      q: 0.7
+  fir:
+    type: Conv
+    parameters:
+      type: Values
+      values: [0.5, 0.3, 0.2]
+  gain:
+    type: Gain
+    parameters:
+      gain: -6
+pipeline:
+  - type: Filter
+    channel: 0
+    names: [lowpass]
+  - type: Filter
+    channel: 2
+    names: [fir, gain]
+  - type: Filter
+    channel: 0
+    names: [fir]
+"#;
+        let mut conf: Configuration = serde_yaml::from_str(yaml).unwrap();
+        let sequential = process_impulse(&conf);
+        conf.devices.multithreaded = true;
+        conf.devices.worker_threads = Some(2);
+        let parallel = process_impulse(&conf);
+        assert_eq!(sequential, parallel);
+        // the untouched channel passes through
+        assert_eq!(parallel[1][1], 1.0);
+    }
+
+    #[test]
+    fn worker_pool_is_reused() {
+        let cache = Mutex::new(None);
+        let first = get_cached_pool(&cache, Some(2));
+        let second = get_cached_pool(&cache, Some(2));
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.current_num_threads(), 2);
+        // a different number of threads replaces the pool
+        let third = get_cached_pool(&cache, Some(3));
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert_eq!(third.current_num_threads(), 3);
+        let fourth = get_cached_pool(&cache, Some(3));
+        assert!(Arc::ptr_eq(&third, &fourth));
+    }
 }
@@ -0,0 +1,203 @@
+use crate::filters::Filter;
+use config;
+use helpers::time_to_coeff;
+
+use NewValue;
+use PrcFmt;
+use Res;
+
+/// Decay time in ms of the peak level detector.
+const DETECTOR_DECAY: PrcFmt = 10.0;
+
+/// A noise gate with hysteresis and hold time.
+/// When the gate is closed, the signal is attenuated by `range` dB.
+#[derive(Clone, Debug)]
+pub struct Gate {
+    pub name: String,
+    samplerate: usize,
+    open_level: PrcFmt,
+    close_level: PrcFmt,
+    attack: PrcFmt,
+    release: PrcFmt,
+    hold: usize,
+    closed_gain: PrcFmt,
+    detector_decay: PrcFmt,
+    envelope: PrcFmt,
+    is_open: bool,
+    hold_counter: usize,
+    gain: PrcFmt,
+}
+
+impl Gate {
+    /// Creates a Gate from a config struct
+    pub fn from_config(name: String, samplerate: usize, config: config::GateParameters) -> Self {
+        let mut gate = Gate {
+            name,
+            samplerate,
+            open_level: 0.0,
+            close_level: 0.0,
+            attack: 0.0,
+            release: 0.0,
+            hold: 0,
+            closed_gain: 0.0,
+            detector_decay: time_to_coeff(DETECTOR_DECAY, samplerate),
+            envelope: 0.0,
+            is_open: false,
+            hold_counter: 0,
+            gain: 0.0,
+        };
+        gate.set_parameters(config);
+        // start in the closed state
+        gate.gain = gate.closed_gain;
+        gate
+    }
+
+    fn set_parameters(&mut self, config: config::GateParameters) {
+        self.open_level = PrcFmt::new(10.0).powf(config.threshold / 20.0);
+        self.close_level = PrcFmt::new(10.0).powf((config.threshold - config.hysteresis) / 20.0);
+        self.attack = time_to_coeff(config.attack, self.samplerate);
+        self.release = time_to_coeff(config.release, self.samplerate);
+        self.hold = (config.hold / 1000.0 * self.samplerate as PrcFmt).round() as usize;
+        self.closed_gain = PrcFmt::new(10.0).powf(config.range / 20.0);
+        debug!(
+            "Gate '{}', threshold: {} dB, hysteresis: {} dB, range: {} dB",
+            self.name, config.threshold, config.hysteresis, config.range
+        );
+    }
+
+    /// Process a single sample
+    fn process_single(&mut self, input: PrcFmt) -> PrcFmt {
+        self.envelope = input.abs().max(self.detector_decay * self.envelope);
+        if self.envelope >= self.open_level {
+            self.is_open = true;
+            self.hold_counter = self.hold;
+        } else if self.is_open && self.envelope < self.close_level {
+            if self.hold_counter > 0 {
+                self.hold_counter -= 1;
+            } else {
+                self.is_open = false;
+            }
+        } else if self.is_open {
+            // between the levels, stay open and restart the hold time
+            self.hold_counter = self.hold;
+        }
+        let (target, coeff) = if self.is_open {
+            (1.0, self.attack)
+        } else {
+            (self.closed_gain, self.release)
+        };
+        self.gain = coeff * self.gain + (1.0 - coeff) * target;
+        self.gain * input
+    }
+}
+
+impl Filter for Gate {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn process_waveform(&mut self, waveform: &mut Vec<PrcFmt>) -> Res<()> {
+        for item in waveform.iter_mut() {
+            *item = self.process_single(*item);
+        }
+        Ok(())
+    }
+
+    fn update_parameters(&mut self, conf: config::Filter) {
+        if let config::Filter::Gate { parameters: conf } = conf {
+            self.set_parameters(conf);
+        } else {
+            // This should never happen unless there is a bug somewhere else
+            panic!("Invalid config change!");
+        }
+    }
+}
+
+/// Validate the gate config, to give a helpful message intead of a panic.
+pub fn validate_config(parameters: &config::GateParameters) -> Res<()> {
+    if parameters.hysteresis < 0.0 {
+        return Err(config::ConfigError::new("Gate hysteresis cannot be negative").into());
+    }
+    if parameters.attack < 0.0 || parameters.hold < 0.0 || parameters.release < 0.0 {
+        return Err(config::ConfigError::new(
+            "Gate attack, hold and release times cannot be negative",
+        )
+        .into());
+    }
+    if parameters.range > 0.0 {
+        return Err(config::ConfigError::new("Gate range must be 0 dB or lower").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use config::GateParameters;
+    use filters::Filter;
+    use gate::{validate_config, Gate};
+    use PrcFmt;
+
+    fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    fn make_params(hysteresis: PrcFmt, hold: PrcFmt) -> GateParameters {
+        GateParameters {
+            threshold: -20.0,
+            hysteresis,
+            attack: 0.0,
+            hold,
+            release: 0.0,
+            range: -40.0,
+        }
+    }
+
+    // A sample rate of 10 Hz makes the time constants 100 ms per sample,
+    // and the level detector fast enough to follow each sample.
+    #[test]
+    fn opens_and_closes() {
+        let mut gate = Gate::from_config("test".to_string(), 10, make_params(0.0, 0.0));
+        let mut waveform = vec![0.01, 0.5, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        gate.process_waveform(&mut waveform).unwrap();
+        // closed, attenuated by 40 dB
+        assert!(is_close(waveform[0], 0.0001, 1.0e-9));
+        // open
+        assert!(is_close(waveform[1], 0.5, 1.0e-9));
+        assert!(is_close(waveform[2], 0.5, 1.0e-9));
+        // at this low sample rate the detector decays to zero within one sample
+        assert!(is_close(waveform[3], 0.0, 1.0e-9));
+        assert!(!gate.is_open);
+    }
+
+    #[test]
+    fn hysteresis_and_hold() {
+        let mut gate = Gate::from_config("test".to_string(), 10, make_params(6.0, 300.0));
+        // the level just below the threshold does not open a closed gate
+        let mut waveform = vec![0.09; 5];
+        gate.process_waveform(&mut waveform).unwrap();
+        assert!(!gate.is_open);
+        // open it, then go just below the threshold, which is within the hysteresis
+        let mut waveform = vec![0.2, 0.09, 0.09, 0.09, 0.09, 0.09, 0.09, 0.09];
+        gate.process_waveform(&mut waveform).unwrap();
+        assert!(gate.is_open);
+        assert!(is_close(waveform[7], 0.09, 1.0e-9));
+        // drop below the closing level, the gate stays open for the hold time
+        let mut waveform = vec![0.01; 4];
+        gate.process_waveform(&mut waveform).unwrap();
+        assert!(is_close(waveform[2], 0.01, 1.0e-9));
+        assert!(is_close(waveform[3], 0.0001, 1.0e-9));
+        assert!(!gate.is_open);
+    }
+
+    #[test]
+    fn check_config() {
+        let mut conf = make_params(6.0, 10.0);
+        assert!(validate_config(&conf).is_ok());
+        conf.range = 10.0;
+        assert!(validate_config(&conf).is_err());
+        conf.range = -40.0;
+        conf.hold = -1.0;
+        assert!(validate_config(&conf).is_err());
+    }
+}
@@ -64,3 +64,13 @@ pub fn multiply_add_elements(
         *r += val;
     }
 }
+
+/// Get the coefficient of a first order smoothing filter with a time constant in milliseconds.
+/// A time of zero or less gives no smoothing.
+pub fn time_to_coeff(time_ms: PrcFmt, samplerate: usize) -> PrcFmt {
+    if time_ms > 0.0 {
+        (-1000.0 / (time_ms * samplerate as PrcFmt)).exp()
+    } else {
+        0.0
+    }
+}
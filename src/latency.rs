@@ -0,0 +1,252 @@
+use audiodevice::{get_async_parameters, resampler_is_async};
+use config;
+use filters;
+use limiter;
+use num_integer as integer;
+use serde::Serialize;
+use std::fmt;
+
+use PrcFmt;
+
+/// Estimated latency of the running system, all values in milliseconds.
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct Latency {
+    /// Time to capture one chunk.
+    pub capture: f32,
+    /// Delay of the resampler, zero when resampling is disabled.
+    pub resampler: f32,
+    /// Largest delay of the filters and processors in the pipeline, over all playback channels.
+    pub filters: f32,
+    /// Delay of the playback device buffer, at the target level.
+    pub playback: f32,
+    /// Sum of capture, resampler, filters and playback.
+    pub total: f32,
+    /// Additional delay when the queues between the threads are full.
+    pub queue_max: f32,
+    /// Delay of the filters and processors for each playback channel.
+    pub channels: Vec<f32>,
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} ms (capture: {:.1} ms, resampler: {:.1} ms, filters: {:.1} ms, playback: {:.1} ms), up to {:.1} ms more when the queues are full",
+            self.total, self.capture, self.resampler, self.filters, self.playback, self.queue_max
+        )
+    }
+}
+
+/// Get the delay in samples at the capture samplerate of the resampler.
+/// The resamplers use symmetric sinc filters, delaying the signal by half the filter length.
+fn resampler_delay(devices: &config::Devices, capture_samplerate: usize) -> usize {
+    if resampler_is_async(&devices.resampler_type) {
+        let parameters = get_async_parameters(
+            &devices.resampler_type,
+            devices.samplerate,
+            capture_samplerate,
+        );
+        parameters.sinc_len / 2
+    } else {
+        // Same block size calculation as used by the synchronous FftFixedOut resampler
+        let gcd = integer::gcd(capture_samplerate, devices.samplerate);
+        let min_chunk_out = devices.samplerate / gcd;
+        let wanted_subsize = devices.chunksize / 2;
+        let fft_chunks = (wanted_subsize as f32 / min_chunk_out as f32).ceil() as usize;
+        let fft_size_in = fft_chunks * capture_samplerate / gcd;
+        fft_size_in / 2
+    }
+}
+
+/// Get the intrinsic delay of a filter in samples.
+/// Delay and Limiter filters delay the signal by their settings,
+/// and a Conv filter is assumed to be delayed until the peak of its impulse response.
+/// Other filters are counted as having no delay.
+pub fn filter_delay(conf: &config::Filter, samplerate: usize) -> PrcFmt {
+    match conf {
+        config::Filter::Delay { parameters } => match parameters.unit {
+            config::TimeUnit::Milliseconds => parameters.delay / 1000.0 * samplerate as PrcFmt,
+            config::TimeUnit::Samples => parameters.delay,
+        },
+        config::Filter::Limiter { parameters } => {
+            limiter::audio_delay(samplerate, parameters) as PrcFmt
+        }
+        config::Filter::Conv { parameters, .. } => {
+            match filters::read_conv_coefficients(parameters) {
+                Ok(coeffs) => {
+                    let mut peak_idx = 0;
+                    let mut peak = 0.0;
+                    for (idx, value) in coeffs.iter().enumerate() {
+                        if value.abs() > peak {
+                            peak = value.abs();
+                            peak_idx = idx;
+                        }
+                    }
+                    peak_idx as PrcFmt
+                }
+                Err(err) => {
+                    warn!(
+                        "Unable to read Conv coefficients for latency calculation: {}",
+                        err
+                    );
+                    0.0
+                }
+            }
+        }
+        _ => 0.0,
+    }
+}
+
+/// Follow the signal through the pipeline, and get the delay in samples of each output channel.
+/// The delay of a mixer output is taken as the largest delay of the sources mixed into it.
+pub fn pipeline_delays(conf: &config::Configuration) -> Vec<PrcFmt> {
+    let samplerate = conf.devices.samplerate;
+    let mut delays = vec![0.0; conf.devices.capture.channels()];
+    for step in conf.pipeline.iter() {
+        match step {
+            config::PipelineStep::Mixer { name } => {
+                let mixer = &conf.mixers[name];
+                let mut new_delays = vec![0.0; mixer.channels.out];
+                for mapping in mixer.mapping.iter() {
+                    new_delays[mapping.dest] = mapping
+                        .sources
+                        .iter()
+                        .filter(|source| !source.mute)
+                        .map(|source| delays[source.channel])
+                        .fold(0.0, PrcFmt::max);
+                }
+                delays = new_delays;
+            }
+            config::PipelineStep::Filter { channel, names } => {
+                for name in names.iter() {
+                    delays[*channel] += filter_delay(&conf.filters[name], samplerate);
+                }
+            }
+            config::PipelineStep::Processor { .. } => {}
+        }
+    }
+    delays
+}
+
+/// Estimate the latency of the system for a validated config.
+pub fn calculate_latency(conf: &config::Configuration) -> Latency {
+    let devices = &conf.devices;
+    let samplerate = devices.samplerate as f32;
+    let to_ms = |frames: f32, rate: f32| 1000.0 * frames / rate;
+    let capture = to_ms(devices.chunksize as f32, samplerate);
+    let resampler = if devices.enable_resampling {
+        let capture_samplerate = if devices.capture_samplerate > 0 {
+            devices.capture_samplerate
+        } else {
+            devices.samplerate
+        };
+        to_ms(
+            resampler_delay(devices, capture_samplerate) as f32,
+            capture_samplerate as f32,
+        )
+    } else {
+        0.0
+    };
+    let channels = pipeline_delays(conf)
+        .iter()
+        .map(|delay| to_ms(*delay as f32, samplerate))
+        .collect::<Vec<f32>>();
+    let filters = channels.iter().cloned().fold(0.0, f32::max);
+    let target_level = if devices.target_level > 0 {
+        devices.target_level
+    } else {
+        devices.chunksize
+    };
+    let playback = to_ms(target_level as f32, samplerate);
+    let queue_max = to_ms(
+        (2 * devices.queuelimit * devices.chunksize) as f32,
+        samplerate,
+    );
+    Latency {
+        capture,
+        resampler,
+        filters,
+        playback,
+        total: capture + resampler + filters + playback,
+        queue_max,
+        channels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Configuration;
+    use latency::calculate_latency;
+
+    fn is_close(left: f32, right: f32, maxdiff: f32) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    #[test]
+    fn pipeline_latency() {
+        let yaml = r#"
+devices:
+  samplerate: 48000
+  chunksize: 480
+  queuelimit: 2
+  target_level: 960
+  capture:
+    type: Stdin
+    channels: 2
+    format: FLOAT64LE
+  playback:
+    type: Stdout
+    channels: 2
+    format: FLOAT64LE
+filters:
+  delay:
+    type: Delay
+    parameters:
+      delay: 10
+      unit: ms
+  fir:
+    type: Conv
+    parameters:
+      type: Values
+      values: [0.0, 0.1, 0.0, 0.0, 1.0, 0.1]
+mixers:
+  swap:
+    channels:
+      in: 2
+      out: 2
+    mapping:
+      - dest: 0
+        sources:
+          - channel: 1
+            gain: 0
+            inverted: false
+      - dest: 1
+        sources:
+          - channel: 0
+            gain: 0
+            inverted: false
+          - channel: 1
+            gain: 0
+            inverted: false
+            mute: true
+pipeline:
+  - type: Filter
+    channel: 0
+    names: [delay, fir]
+  - type: Mixer
+    name: swap
+"#;
+        let conf: Configuration = serde_yaml::from_str(yaml).unwrap();
+        let latency = calculate_latency(&conf);
+        assert!(is_close(latency.capture, 10.0, 1.0e-4));
+        assert!(is_close(latency.playback, 20.0, 1.0e-4));
+        assert!(is_close(latency.resampler, 0.0, 1.0e-4));
+        assert!(is_close(latency.queue_max, 40.0, 1.0e-4));
+        // 10 ms delay plus the 4 sample peak of the FIR, on channel 0 that is moved to channel 1
+        assert!(is_close(latency.channels[0], 0.0, 1.0e-4));
+        assert!(is_close(latency.channels[1], 10.0 + 4.0 / 48.0, 1.0e-4));
+        assert!(is_close(latency.filters, latency.channels[1], 1.0e-6));
+        assert!(is_close(latency.total, 40.0 + 4.0 / 48.0, 1.0e-4));
+    }
+}
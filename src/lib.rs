@@ -9,6 +9,8 @@ extern crate fftw;
 extern crate lazy_static;
 #[cfg(target_os = "windows")]
 extern crate crossbeam_channel;
+#[cfg(feature = "websocket")]
+extern crate json_patch;
 #[cfg(feature = "pulse-backend")]
 extern crate libpulse_binding as pulse;
 #[cfg(feature = "pulse-backend")]
@@ -23,6 +25,7 @@ extern crate num_traits;
 extern crate rand;
 extern crate rand_distr;
 extern crate rawsample;
+extern crate rayon;
 #[cfg(not(feature = "FFTW"))]
 extern crate realfft;
 extern crate rubato;
@@ -37,10 +40,14 @@ extern crate wasapi;
 #[macro_use]
 extern crate slog_scope;
 
-use serde::Serialize;
+use loudnessmeter::LoudnessLevels;
+use serde::{Deserialize, Serialize};
+use spectrum::Spectrum;
+use std::collections::HashMap;
 use std::error;
 use std::fmt;
-use std::sync::{Arc, RwLock};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
 
 // Sample format
 #[cfg(feature = "32bit")]
@@ -60,17 +67,24 @@ impl<PrcFmt> NewValue<PrcFmt> for PrcFmt {
 
 pub type Res<T> = Result<T, Box<dyn error::Error>>;
 
+/// Sender for config changes to the processing thread of the current run,
+/// for applying changes that don't need a reload of the whole config.
+pub type PipelineUpdates =
+    Arc<Mutex<Option<mpsc::Sender<(config::ConfigChange, config::Configuration)>>>>;
+
 #[cfg(all(feature = "alsa-backend", target_os = "linux"))]
 pub mod alsadevice;
 pub mod audiodevice;
 pub mod basicfilters;
 pub mod biquad;
 pub mod biquadcombo;
+pub mod compressor;
 pub mod config;
 pub mod conversions;
 pub mod countertimer;
 #[cfg(feature = "cpal-backend")]
 pub mod cpaldevice;
+pub mod crossfeed;
 pub mod diffeq;
 pub mod dither;
 #[cfg(not(feature = "FFTW"))]
@@ -80,14 +94,25 @@ pub mod fftconv_fftw;
 pub mod fifoqueue;
 pub mod filedevice;
 pub mod filters;
+pub mod gate;
 pub mod helpers;
+pub mod latency;
+pub mod limiter;
 pub mod loudness;
+pub mod loudnessmeter;
+pub mod metrics;
 pub mod mixer;
+pub mod nonuniformconv;
+pub mod nulldevice;
 pub mod processing;
 #[cfg(feature = "pulse-backend")]
 pub mod pulsedevice;
+pub mod response;
+pub mod signalgenerator;
 #[cfg(feature = "websocket")]
 pub mod socketserver;
+pub mod spectrum;
+pub mod statefile;
 #[cfg(target_os = "windows")]
 pub mod wasapidevice;
 
@@ -139,6 +164,7 @@ pub struct CaptureStatus {
     pub state: ProcessingState,
     pub rate_adjust: f32,
     pub used_channels: Vec<bool>,
+    pub loudness: LoudnessLevels,
 }
 
 #[derive(Clone, Debug)]
@@ -148,17 +174,89 @@ pub struct PlaybackStatus {
     pub buffer_level: usize,
     pub signal_rms: Vec<f32>,
     pub signal_peak: Vec<f32>,
+    pub loudness: LoudnessLevels,
+}
+
+/// Name of the main fader, controlled by the `volume` and `mute` parameters.
+pub const MAIN_FADER: &str = "Main";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fader {
+    pub volume: f32,
+    pub mute: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct ProcessingParameters {
     pub volume: f32,
     pub mute: bool,
+    /// Additional named faders, used by Volume and Loudness filters that don't use the main fader.
+    pub faders: HashMap<String, Fader>,
+}
+
+impl ProcessingParameters {
+    /// Get the current setting of a fader, None if there is no fader with this name.
+    pub fn get_fader(&self, name: &str) -> Option<Fader> {
+        if name == MAIN_FADER {
+            Some(Fader {
+                volume: self.volume,
+                mute: self.mute,
+            })
+        } else {
+            self.faders.get(name).cloned()
+        }
+    }
+
+    /// Add a fader if it doesn't already exist, starting at 0 dB and unmuted.
+    pub fn add_fader(&mut self, name: &str) {
+        if name != MAIN_FADER && !self.faders.contains_key(name) {
+            debug!("Adding fader '{}'", name);
+            self.faders.insert(name.to_string(), Fader::default());
+        }
+    }
+
+    /// Set the volume of a fader, returns false if there is no fader with this name.
+    pub fn set_fader_volume(&mut self, name: &str, volume: f32) -> bool {
+        if name == MAIN_FADER {
+            self.volume = volume;
+            true
+        } else if let Some(fader) = self.faders.get_mut(name) {
+            fader.volume = volume;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Set the mute of a fader, returns false if there is no fader with this name.
+    pub fn set_fader_mute(&mut self, name: &str, mute: bool) -> bool {
+        if name == MAIN_FADER {
+            self.mute = mute;
+            true
+        } else if let Some(fader) = self.faders.get_mut(name) {
+            fader.mute = mute;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Time spent processing a chunk, in percent of the duration of the chunk.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ProcessingLoad {
+    pub min: f32,
+    pub average: f32,
+    pub max: f32,
 }
 
 #[derive(Clone, Debug)]
 pub struct ProcessingStatus {
     pub stop_reason: StopReason,
+    pub processing_load: ProcessingLoad,
+    pub capture_spectrum: Spectrum,
+    pub playback_spectrum: Spectrum,
+    pub reset_loudness: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
@@ -192,8 +290,18 @@ impl fmt::Display for ProcessingState {
 }
 
 pub fn list_supported_devices() -> (Vec<String>, Vec<String>) {
-    let mut playbacktypes = vec!["File".to_owned(), "Stdout".to_owned()];
-    let mut capturetypes = vec!["File".to_owned(), "Stdin".to_owned()];
+    let mut playbacktypes = vec![
+        "File".to_owned(),
+        "Wav".to_owned(),
+        "Stdout".to_owned(),
+        "Null".to_owned(),
+    ];
+    let mut capturetypes = vec![
+        "File".to_owned(),
+        "Wav".to_owned(),
+        "Stdin".to_owned(),
+        "SignalGenerator".to_owned(),
+    ];
 
     if cfg!(all(feature = "alsa-backend", target_os = "linux")) {
         playbacktypes.push("Alsa".to_owned());
@@ -0,0 +1,341 @@
+use crate::filters::Filter;
+use config;
+use helpers::time_to_coeff;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use NewValue;
+use PrcFmt;
+use Res;
+
+/// Oversampling factor used for true peak detection.
+const TP_OVERSAMPLING: usize = 4;
+/// Number of taps per phase of the true peak interpolation filter.
+const TP_TAPS: usize = 8;
+
+/// A brickwall peak limiter with lookahead.
+/// The required gain for each sample is held as a sliding minimum over the lookahead window,
+/// released smoothly, and then averaged over the same window.
+/// The audio is delayed by the lookahead time, so that the gain has reached its target
+/// when a peak reaches the output.
+#[derive(Clone, Debug)]
+pub struct Limiter {
+    pub name: String,
+    samplerate: usize,
+    ceiling: PrcFmt,
+    release: PrcFmt,
+    lookahead_ms: PrcFmt,
+    true_peak: bool,
+    lookahead: usize,
+    // delayed audio
+    delay: Vec<PrcFmt>,
+    delay_idx: usize,
+    // sliding minimum of the required gain, as (sample index, gain)
+    gain_min: VecDeque<(usize, PrcFmt)>,
+    sample_idx: usize,
+    released_gain: PrcFmt,
+    // moving average of the released gain
+    gain_avg: Vec<PrcFmt>,
+    gain_avg_idx: usize,
+    gain_sum: PrcFmt,
+    // true peak detection
+    tp_coeffs: Vec<Vec<PrcFmt>>,
+    tp_history: Vec<PrcFmt>,
+    tp_history_idx: usize,
+    tp_prev_peak: PrcFmt,
+}
+
+/// Calculate the polyphase coefficients of a Hann windowed sinc interpolator,
+/// for the points between the input samples.
+fn true_peak_coeffs() -> Vec<Vec<PrcFmt>> {
+    let half = (TP_TAPS / 2) as f64;
+    let mut phases = Vec::with_capacity(TP_OVERSAMPLING - 1);
+    for phase in 1..TP_OVERSAMPLING {
+        let frac = phase as f64 / TP_OVERSAMPLING as f64;
+        let mut coeffs = Vec::with_capacity(TP_TAPS);
+        for tap in 0..TP_TAPS {
+            // taps cover input samples at offsets -3..=4 relative to the interval start
+            let t = frac - (tap as f64 - half + 1.0);
+            let sinc = if t == 0.0 {
+                1.0
+            } else {
+                (PI * t).sin() / (PI * t)
+            };
+            let window = 0.5 * (1.0 + (PI * t / half).cos());
+            coeffs.push((sinc * window) as PrcFmt);
+        }
+        phases.push(coeffs);
+    }
+    phases
+}
+
+/// Get the lookahead in samples.
+fn lookahead_samples(samplerate: usize, config: &config::LimiterParameters) -> usize {
+    (config.lookahead / 1000.0 * samplerate as PrcFmt).round() as usize
+}
+
+/// Get the delay of the audio through the limiter in samples.
+pub fn audio_delay(samplerate: usize, config: &config::LimiterParameters) -> usize {
+    // the true peak detector needs to see half its taps ahead of the detected sample
+    let detector_delay = if config.true_peak { TP_TAPS / 2 } else { 0 };
+    lookahead_samples(samplerate, config) + detector_delay
+}
+
+impl Limiter {
+    /// Creates a Limiter from a config struct
+    pub fn from_config(name: String, samplerate: usize, config: config::LimiterParameters) -> Self {
+        let lookahead = lookahead_samples(samplerate, &config);
+        let ceiling = PrcFmt::new(10.0).powf(config.ceiling / 20.0);
+        let release = time_to_coeff(config.release, samplerate);
+        debug!(
+            "Creating limiter '{}', ceiling: {} dB, lookahead: {} samples, true peak: {}",
+            name, config.ceiling, lookahead, config.true_peak
+        );
+        Limiter {
+            name,
+            samplerate,
+            ceiling,
+            release,
+            lookahead_ms: config.lookahead,
+            true_peak: config.true_peak,
+            lookahead,
+            delay: vec![0.0; audio_delay(samplerate, &config)],
+            delay_idx: 0,
+            gain_min: VecDeque::with_capacity(lookahead + 1),
+            sample_idx: 0,
+            released_gain: 1.0,
+            gain_avg: vec![1.0; lookahead + 1],
+            gain_avg_idx: 0,
+            gain_sum: (lookahead + 1) as PrcFmt,
+            tp_coeffs: true_peak_coeffs(),
+            tp_history: vec![0.0; TP_TAPS],
+            tp_history_idx: 0,
+            tp_prev_peak: 0.0,
+        }
+    }
+
+    /// Estimate the peak value, including the inter-sample peaks on both sides,
+    /// of the sample TP_TAPS/2 samples back.
+    fn true_peak(&mut self, value: PrcFmt) -> PrcFmt {
+        self.tp_history[self.tp_history_idx] = value;
+        self.tp_history_idx = (self.tp_history_idx + 1) % TP_TAPS;
+        // after the increment, tp_history_idx points at the oldest sample
+        let mut interval_peak: PrcFmt = 0.0;
+        for coeffs in self.tp_coeffs.iter() {
+            let mut interp = 0.0;
+            for (n, coeff) in coeffs.iter().enumerate() {
+                interp += coeff * self.tp_history[(self.tp_history_idx + n) % TP_TAPS];
+            }
+            interval_peak = interval_peak.max(interp.abs());
+        }
+        let center = self.tp_history[(self.tp_history_idx + TP_TAPS / 2 - 1) % TP_TAPS].abs();
+        let peak = center.max(interval_peak).max(self.tp_prev_peak);
+        self.tp_prev_peak = interval_peak;
+        peak
+    }
+
+    /// Push a new required gain value and get the minimum over the lookahead window.
+    fn sliding_min(&mut self, gain: PrcFmt) -> PrcFmt {
+        while let Some(&(_, last)) = self.gain_min.back() {
+            if last >= gain {
+                self.gain_min.pop_back();
+            } else {
+                break;
+            }
+        }
+        self.gain_min.push_back((self.sample_idx, gain));
+        while let Some(&(idx, _)) = self.gain_min.front() {
+            if idx + self.lookahead < self.sample_idx {
+                self.gain_min.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.sample_idx = self.sample_idx.wrapping_add(1);
+        if self.sample_idx == 0 {
+            // the index wrapped around, restart the window
+            self.gain_min.clear();
+        }
+        self.gain_min.front().map(|val| val.1).unwrap_or(gain)
+    }
+
+    /// Process a single sample
+    fn process_single(&mut self, input: PrcFmt) -> PrcFmt {
+        let peak = if self.true_peak {
+            self.true_peak(input)
+        } else {
+            input.abs()
+        };
+        let required_gain = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+        let held_gain = self.sliding_min(required_gain);
+        self.released_gain = if held_gain < self.released_gain {
+            held_gain
+        } else {
+            self.release * self.released_gain + (1.0 - self.release) * held_gain
+        };
+        self.gain_sum += self.released_gain - self.gain_avg[self.gain_avg_idx];
+        self.gain_avg[self.gain_avg_idx] = self.released_gain;
+        self.gain_avg_idx = (self.gain_avg_idx + 1) % self.gain_avg.len();
+        let gain = self.gain_sum / self.gain_avg.len() as PrcFmt;
+
+        let delayed = if self.delay.is_empty() {
+            input
+        } else {
+            let delayed = self.delay[self.delay_idx];
+            self.delay[self.delay_idx] = input;
+            self.delay_idx = (self.delay_idx + 1) % self.delay.len();
+            delayed
+        };
+        // The gain already keeps the sample peaks below the ceiling.
+        // Clamp anyway to guard against rounding errors in the running sum.
+        (gain * delayed).max(-self.ceiling).min(self.ceiling)
+    }
+}
+
+impl Filter for Limiter {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn process_waveform(&mut self, waveform: &mut Vec<PrcFmt>) -> Res<()> {
+        for item in waveform.iter_mut() {
+            *item = self.process_single(*item);
+        }
+        Ok(())
+    }
+
+    fn update_parameters(&mut self, conf: config::Filter) {
+        if let config::Filter::Limiter { parameters: conf } = conf {
+            if conf.lookahead != self.lookahead_ms || conf.true_peak != self.true_peak {
+                // The delay changes, start over with a new limiter
+                let name = self.name.clone();
+                *self = Limiter::from_config(name, self.samplerate, conf);
+            } else {
+                self.ceiling = PrcFmt::new(10.0).powf(conf.ceiling / 20.0);
+                self.release = time_to_coeff(conf.release, self.samplerate);
+                debug!(
+                    "Updated limiter '{}', ceiling: {} dB, release: {} ms",
+                    self.name, conf.ceiling, conf.release
+                );
+            }
+        } else {
+            // This should never happen unless there is a bug somewhere else
+            panic!("Invalid config change!");
+        }
+    }
+}
+
+/// Validate the limiter config, to give a helpful message intead of a panic.
+pub fn validate_config(parameters: &config::LimiterParameters) -> Res<()> {
+    if parameters.ceiling > 0.0 {
+        return Err(config::ConfigError::new("Limiter ceiling must be 0 dB or lower").into());
+    }
+    if parameters.lookahead < 0.0 {
+        return Err(config::ConfigError::new("Limiter lookahead cannot be negative").into());
+    }
+    if parameters.release < 0.0 {
+        return Err(config::ConfigError::new("Limiter release time cannot be negative").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use config::LimiterParameters;
+    use filters::Filter;
+    use limiter::{validate_config, Limiter};
+    use std::f64::consts::{FRAC_1_SQRT_2, PI};
+    use PrcFmt;
+
+    fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    fn make_limiter(ceiling: PrcFmt, lookahead: PrcFmt, true_peak: bool) -> Limiter {
+        let conf = LimiterParameters {
+            ceiling,
+            lookahead,
+            release: 10.0,
+            true_peak,
+        };
+        Limiter::from_config("test".to_string(), 1000, conf)
+    }
+
+    #[test]
+    fn below_ceiling_is_delayed() {
+        let mut limiter = make_limiter(0.0, 3.0, false);
+        let mut waveform = vec![0.5, -0.25, 0.125, 0.0, 0.0, 0.0];
+        let expected = [0.0, 0.0, 0.0, 0.5, -0.25, 0.125];
+        limiter.process_waveform(&mut waveform).unwrap();
+        for (val, exp) in waveform.iter().zip(expected.iter()) {
+            assert!(is_close(*val, *exp, 1.0e-9));
+        }
+    }
+
+    #[test]
+    fn peaks_never_exceed_ceiling() {
+        let mut limiter = make_limiter(-6.0, 4.0, false);
+        let ceiling = (10.0 as PrcFmt).powf(-6.0 / 20.0);
+        let mut waveform: Vec<PrcFmt> = (0..200)
+            .map(|n| 2.0 * (n as PrcFmt * 0.3).sin() * (n as PrcFmt * 0.01).cos())
+            .collect();
+        limiter.process_waveform(&mut waveform).unwrap();
+        for val in waveform.iter() {
+            assert!(val.abs() <= ceiling + 1.0e-9);
+        }
+    }
+
+    #[test]
+    fn gain_ramps_before_peak() {
+        let mut limiter = make_limiter(-6.0, 4.0, false);
+        let mut waveform = vec![0.25; 20];
+        waveform[10] = 1.0;
+        limiter.process_waveform(&mut waveform).unwrap();
+        // the peak at 10 is output at 14, the gain starts decreasing 4 samples earlier
+        assert!(is_close(waveform[9], 0.25, 1.0e-9));
+        assert!(waveform[10] < 0.25);
+        assert!(waveform[10] > 0.8 * 0.25);
+        assert!(waveform[14] <= 0.5012);
+    }
+
+    #[test]
+    fn true_peak_detects_intersample_peak() {
+        let mut sample_limiter = make_limiter(-1.0, 2.0, false);
+        let mut tp_limiter = make_limiter(-1.0, 2.0, true);
+        // fs/4 sine with 45 degree phase, the sample values are 0.707 of the true peak
+        let mut waveform: Vec<PrcFmt> = (0..64)
+            .map(|n| (PI * (n as f64 / 2.0 + 0.25)).sin() as PrcFmt)
+            .collect();
+        let mut waveform_tp = waveform.clone();
+        sample_limiter.process_waveform(&mut waveform).unwrap();
+        tp_limiter.process_waveform(&mut waveform_tp).unwrap();
+        // the sample peaks are below the ceiling, but the true peak is above
+        assert!(is_close(
+            waveform[40].abs(),
+            FRAC_1_SQRT_2 as PrcFmt,
+            1.0e-3
+        ));
+        assert!(waveform_tp[40].abs() < 0.95 * FRAC_1_SQRT_2 as PrcFmt);
+    }
+
+    #[test]
+    fn check_config() {
+        let mut conf = LimiterParameters {
+            ceiling: -1.0,
+            lookahead: 5.0,
+            release: 50.0,
+            true_peak: true,
+        };
+        assert!(validate_config(&conf).is_ok());
+        conf.ceiling = 1.0;
+        assert!(validate_config(&conf).is_err());
+        conf.ceiling = -1.0;
+        conf.lookahead = -1.0;
+        assert!(validate_config(&conf).is_err());
+    }
+}
@@ -19,6 +19,7 @@ pub struct Loudness {
     ramp_step: usize,
     samplerate: usize,
     chunksize: usize,
+    fader: String,
     processing_status: Arc<RwLock<ProcessingParameters>>,
     reference_level: f32,
     high_boost: f32,
@@ -45,8 +46,13 @@ impl Loudness {
         samplerate: usize,
         processing_status: Arc<RwLock<ProcessingParameters>>,
     ) -> Self {
-        let current_volume = processing_status.read().unwrap().volume;
-        let mute = processing_status.read().unwrap().mute;
+        let current = {
+            let mut params = processing_status.write().unwrap();
+            params.add_fader(&conf.fader);
+            params.get_fader(&conf.fader).unwrap_or_default()
+        };
+        let current_volume = current.volume;
+        let mute = current.mute;
         let ramptime_in_chunks =
             (conf.ramp_time / (1000.0 * chunksize as f32 / samplerate as f32)).round() as usize;
         let tempgain: PrcFmt = 10.0;
@@ -84,6 +90,7 @@ impl Loudness {
             ramp_step: 0,
             samplerate,
             chunksize,
+            fader: conf.fader,
             processing_status,
         }
     }
@@ -117,8 +124,14 @@ impl Filter for Loudness {
     }
 
     fn process_waveform(&mut self, waveform: &mut Vec<PrcFmt>) -> Res<()> {
-        let shared_vol = self.processing_status.read().unwrap().volume;
-        let shared_mute = self.processing_status.read().unwrap().mute;
+        let fader = self
+            .processing_status
+            .read()
+            .unwrap()
+            .get_fader(&self.fader)
+            .unwrap_or_default();
+        let shared_vol = fader.volume;
+        let shared_mute = fader.mute;
 
         // Volume setting changed
         if (shared_vol - self.target_volume).abs() > 0.01 || self.mute != shared_mute {
@@ -210,7 +223,12 @@ impl Filter for Loudness {
             self.ramptime_in_chunks = (conf.ramp_time
                 / (1000.0 * self.chunksize as f32 / self.samplerate as f32))
                 .round() as usize;
-            let current_volume = self.processing_status.read().unwrap().volume;
+            let current_volume = {
+                let mut params = self.processing_status.write().unwrap();
+                params.add_fader(&conf.fader);
+                params.get_fader(&conf.fader).unwrap_or_default().volume
+            };
+            self.fader = conf.fader;
             let relboost = get_rel_boost(current_volume, conf.reference_level);
             let highshelf_conf =
                 config::BiquadParameters::Highshelf(config::ShelfSteepness::Slope {
@@ -255,6 +273,8 @@ pub fn validate_config(conf: &config::LoudnessParameters) -> Res<()> {
         return Err(config::ConfigError::new("Low boost cannot be larger than 20").into());
     } else if conf.ramp_time < 0.0 {
         return Err(config::ConfigError::new("Ramp time cannot be negative").into());
+    } else if conf.fader.is_empty() {
+        return Err(config::ConfigError::new("Fader name cannot be empty").into());
     }
     Ok(())
 }
@@ -0,0 +1,409 @@
+use audiodevice::AudioChunk;
+use biquad::{Biquad, BiquadCoefficients};
+use filters::Filter;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+use PrcFmt;
+
+/// Level used for loudness values that are not yet defined, same as for the RMS of a silent signal.
+pub const UNDEFINED_LOUDNESS: f32 = -1000.0;
+
+/// Number of 100 ms blocks in the momentary window of 400 ms.
+const MOMENTARY_BLOCKS: usize = 4;
+
+/// Number of 100 ms blocks in the short-term window of 3 s.
+const SHORT_TERM_BLOCKS: usize = 30;
+
+/// Blocks below this loudness are ignored for the integrated loudness and the loudness range.
+const ABSOLUTE_GATE: PrcFmt = -70.0;
+
+/// Gate relative to the ungated integrated loudness, for the integrated loudness.
+const INTEGRATED_RELATIVE_GATE: PrcFmt = -10.0;
+
+/// Gate relative to the ungated integrated loudness, for the loudness range.
+const RANGE_RELATIVE_GATE: PrcFmt = -20.0;
+
+/// Resolution of the histograms in LU.
+const HISTOGRAM_STEP: PrcFmt = 0.1;
+
+/// Number of histogram bins, covering the range from the absolute gate up to +30 LUFS.
+const HISTOGRAM_BINS: usize = 1000;
+
+/// Loudness levels according to ITU-R BS.1770 and EBU R128.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LoudnessLevels {
+    /// Loudness in LUFS over the last 400 ms.
+    pub momentary: f32,
+    /// Loudness in LUFS over the last 3 s.
+    pub short_term: f32,
+    /// Gated loudness in LUFS since the start or the last reset.
+    pub integrated: f32,
+    /// Loudness range in LU since the start or the last reset.
+    pub range: f32,
+}
+
+impl Default for LoudnessLevels {
+    fn default() -> Self {
+        LoudnessLevels {
+            momentary: UNDEFINED_LOUDNESS,
+            short_term: UNDEFINED_LOUDNESS,
+            integrated: UNDEFINED_LOUDNESS,
+            range: 0.0,
+        }
+    }
+}
+
+/// Convert a mean square value to loudness.
+fn power_to_loudness(power: PrcFmt) -> PrcFmt {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// Convert loudness to a mean square value.
+fn loudness_to_power(loudness: PrcFmt) -> PrcFmt {
+    (10.0 as PrcFmt).powf((loudness + 0.691) / 10.0)
+}
+
+fn to_level(loudness: Option<PrcFmt>) -> f32 {
+    loudness
+        .filter(|value| value.is_finite())
+        .map(|value| value as f32)
+        .unwrap_or(UNDEFINED_LOUDNESS)
+}
+
+/// Get the coefficients of the two stages of the K-weighting filter,
+/// the high shelf of the head model followed by the RLB highpass.
+/// These are calculated for any samplerate, giving the coefficients from the standard at 48 kHz.
+pub fn k_weighting(samplerate: usize) -> (BiquadCoefficients, BiquadCoefficients) {
+    let pi = std::f64::consts::PI as PrcFmt;
+    let fs = samplerate as PrcFmt;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (pi * f0 / fs).tan();
+    let vh = (10.0 as PrcFmt).powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = BiquadCoefficients::new(
+        2.0 * (k * k - 1.0) / a0,
+        (1.0 - k / q + k * k) / a0,
+        (vh + vb * k / q + k * k) / a0,
+        2.0 * (k * k - vh) / a0,
+        (vh - vb * k / q + k * k) / a0,
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (pi * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = BiquadCoefficients::new(
+        2.0 * (k * k - 1.0) / a0,
+        (1.0 - k / q + k * k) / a0,
+        1.0,
+        -2.0,
+        1.0,
+    );
+    (shelf, highpass)
+}
+
+/// Histogram of block loudness values, used for the gated measurements
+/// without having to store every block since the start.
+/// The summed power of the blocks in each bin is kept, so that only the gating is affected by the bin size.
+struct Histogram {
+    counts: Vec<usize>,
+    powers: Vec<PrcFmt>,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            counts: vec![0; HISTOGRAM_BINS],
+            powers: vec![0.0; HISTOGRAM_BINS],
+        }
+    }
+
+    fn clear(&mut self) {
+        for (count, power) in self.counts.iter_mut().zip(self.powers.iter_mut()) {
+            *count = 0;
+            *power = 0.0;
+        }
+    }
+
+    fn add(&mut self, loudness: PrcFmt) {
+        if loudness < ABSOLUTE_GATE {
+            return;
+        }
+        let idx = (((loudness - ABSOLUTE_GATE) / HISTOGRAM_STEP) as usize).min(HISTOGRAM_BINS - 1);
+        self.counts[idx] += 1;
+        self.powers[idx] += loudness_to_power(loudness);
+    }
+
+    fn bin_loudness(idx: usize) -> PrcFmt {
+        ABSOLUTE_GATE + (idx as PrcFmt + 0.5) * HISTOGRAM_STEP
+    }
+
+    /// Get the loudness of the mean power of the blocks above the threshold.
+    fn gated_loudness(&self, threshold: PrcFmt) -> Option<PrcFmt> {
+        let mut power = 0.0;
+        let mut nbr = 0;
+        for (idx, (count, bin_power)) in self.counts.iter().zip(self.powers.iter()).enumerate() {
+            if Histogram::bin_loudness(idx) >= threshold {
+                power += bin_power;
+                nbr += count;
+            }
+        }
+        if nbr == 0 {
+            return None;
+        }
+        Some(power_to_loudness(power / nbr as PrcFmt))
+    }
+
+    /// Get the gated loudness, using a relative gate below the ungated loudness.
+    fn relative_gated_loudness(&self, relative_gate: PrcFmt) -> Option<PrcFmt> {
+        let ungated = self.gated_loudness(ABSOLUTE_GATE)?;
+        self.gated_loudness(ungated + relative_gate)
+    }
+
+    /// Get the difference between the 95th and 10th percentiles,
+    /// of the blocks above the relative gate for the loudness range.
+    fn range(&self) -> Option<PrcFmt> {
+        let threshold = self.gated_loudness(ABSOLUTE_GATE)? + RANGE_RELATIVE_GATE;
+        let first = self
+            .counts
+            .iter()
+            .enumerate()
+            .position(|(idx, _)| Histogram::bin_loudness(idx) >= threshold)?;
+        let total = self.counts[first..].iter().sum::<usize>();
+        if total == 0 {
+            return None;
+        }
+        let percentile = |fraction: PrcFmt| {
+            let target = (fraction * (total - 1) as PrcFmt).round() as usize;
+            let mut cumulative = 0;
+            for (idx, count) in self.counts.iter().enumerate().skip(first) {
+                cumulative += count;
+                if cumulative > target {
+                    return Histogram::bin_loudness(idx);
+                }
+            }
+            Histogram::bin_loudness(HISTOGRAM_BINS - 1)
+        };
+        Some(percentile(0.95) - percentile(0.10))
+    }
+}
+
+/// Loudness meter according to ITU-R BS.1770 and EBU R128.
+/// The signal is measured in blocks of 100 ms, that are combined to give the momentary
+/// and short-term loudness. The gated integrated loudness and the loudness range are
+/// calculated from all blocks since the start, or since the last reset.
+/// All channels are weighted equally.
+pub struct LoudnessMeter {
+    samplerate: usize,
+    filters: Vec<(Biquad, Biquad)>,
+    scratch: Vec<Vec<PrcFmt>>,
+    block_size: usize,
+    block_fill: usize,
+    block_sum: PrcFmt,
+    blocks: VecDeque<PrcFmt>,
+    nbr_blocks: usize,
+    momentary_histogram: Histogram,
+    short_term_histogram: Histogram,
+    levels: LoudnessLevels,
+}
+
+impl LoudnessMeter {
+    pub fn new(samplerate: usize) -> Self {
+        LoudnessMeter {
+            samplerate,
+            filters: Vec::new(),
+            scratch: Vec::new(),
+            block_size: samplerate / 10,
+            block_fill: 0,
+            block_sum: 0.0,
+            blocks: VecDeque::with_capacity(SHORT_TERM_BLOCKS + 1),
+            nbr_blocks: 0,
+            momentary_histogram: Histogram::new(),
+            short_term_histogram: Histogram::new(),
+            levels: LoudnessLevels::default(),
+        }
+    }
+
+    /// Clear the integrated loudness and loudness range, and start a new measurement.
+    pub fn reset(&mut self) {
+        self.momentary_histogram.clear();
+        self.short_term_histogram.clear();
+        self.levels.integrated = UNDEFINED_LOUDNESS;
+        self.levels.range = 0.0;
+    }
+
+    /// Measure the valid frames of a chunk. Returns true if the levels were updated.
+    pub fn process_chunk(&mut self, chunk: &AudioChunk) -> bool {
+        if self.filters.len() != chunk.waveforms.len() {
+            let (shelf, highpass) = k_weighting(self.samplerate);
+            self.filters = (0..chunk.waveforms.len())
+                .map(|_| {
+                    (
+                        Biquad::new("shelf".to_string(), self.samplerate, shelf),
+                        Biquad::new("highpass".to_string(), self.samplerate, highpass),
+                    )
+                })
+                .collect();
+            self.scratch = vec![Vec::new(); chunk.waveforms.len()];
+        }
+        let frames = chunk.valid_frames;
+        for ((waveform, (shelf, highpass)), scratch) in chunk
+            .waveforms
+            .iter()
+            .zip(self.filters.iter_mut())
+            .zip(self.scratch.iter_mut())
+        {
+            scratch.clear();
+            // Channels that are not in use may have empty waveforms, these are left out
+            if waveform.len() >= frames {
+                scratch.extend_from_slice(&waveform[0..frames]);
+                shelf.process_waveform(scratch).unwrap();
+                highpass.process_waveform(scratch).unwrap();
+            }
+        }
+        let mut updated = false;
+        let mut position = 0;
+        while position < frames {
+            let nbr = (self.block_size - self.block_fill).min(frames - position);
+            for scratch in self.scratch.iter().filter(|values| !values.is_empty()) {
+                self.block_sum += scratch[position..position + nbr]
+                    .iter()
+                    .map(|value| value * value)
+                    .sum::<PrcFmt>();
+            }
+            position += nbr;
+            self.block_fill += nbr;
+            if self.block_fill == self.block_size {
+                self.finish_block();
+                updated = true;
+            }
+        }
+        updated
+    }
+
+    /// Store a completed block and update the levels.
+    fn finish_block(&mut self) {
+        self.blocks
+            .push_back(self.block_sum / self.block_size as PrcFmt);
+        if self.blocks.len() > SHORT_TERM_BLOCKS {
+            self.blocks.pop_front();
+        }
+        self.block_sum = 0.0;
+        self.block_fill = 0;
+        self.nbr_blocks += 1;
+
+        let window_loudness = |nbr: usize| {
+            let power = self.blocks.iter().rev().take(nbr).sum::<PrcFmt>() / nbr as PrcFmt;
+            power_to_loudness(power)
+        };
+        let momentary = window_loudness(MOMENTARY_BLOCKS);
+        let short_term = window_loudness(SHORT_TERM_BLOCKS);
+        // The gated measurements only use complete windows
+        if self.nbr_blocks >= MOMENTARY_BLOCKS {
+            self.momentary_histogram.add(momentary);
+        }
+        if self.nbr_blocks >= SHORT_TERM_BLOCKS {
+            self.short_term_histogram.add(short_term);
+        }
+        self.levels = LoudnessLevels {
+            momentary: to_level(Some(momentary)),
+            short_term: to_level(Some(short_term)),
+            integrated: to_level(
+                self.momentary_histogram
+                    .relative_gated_loudness(INTEGRATED_RELATIVE_GATE),
+            ),
+            range: self.short_term_histogram.range().unwrap_or(0.0) as f32,
+        };
+    }
+
+    pub fn get_levels(&self) -> LoudnessLevels {
+        self.levels.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use audiodevice::AudioChunk;
+    use loudnessmeter::{k_weighting, LoudnessMeter, UNDEFINED_LOUDNESS};
+    use PrcFmt;
+
+    fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    /// Feed a stereo 1 kHz sine with the given peak level in dBFS for a number of seconds.
+    fn feed_sine(meter: &mut LoudnessMeter, position: &mut usize, level: PrcFmt, seconds: usize) {
+        let pi = std::f64::consts::PI as PrcFmt;
+        let amplitude = (10.0 as PrcFmt).powf(level / 20.0);
+        for _ in 0..(seconds * 48000 / 1000) {
+            let waveform = (*position..*position + 1000)
+                .map(|n| amplitude * (2.0 * pi * 1000.0 * n as PrcFmt / 48000.0).sin())
+                .collect::<Vec<PrcFmt>>();
+            *position += 1000;
+            let chunk = AudioChunk::new(
+                vec![waveform.clone(), waveform],
+                amplitude,
+                -amplitude,
+                1000,
+                1000,
+            );
+            meter.process_chunk(&chunk);
+        }
+    }
+
+    #[test]
+    fn k_weighting_at_48k() {
+        let (shelf, highpass) = k_weighting(48000);
+        assert!(is_close(shelf.b0, 1.53512485958697, 1.0e-9));
+        assert!(is_close(shelf.b1, -2.69169618940638, 1.0e-9));
+        assert!(is_close(shelf.b2, 1.19839281085285, 1.0e-9));
+        assert!(is_close(shelf.a1, -1.69065929318241, 1.0e-9));
+        assert!(is_close(shelf.a2, 0.73248077421585, 1.0e-9));
+        assert!(is_close(highpass.a1, -1.99004745483398, 1.0e-9));
+        assert!(is_close(highpass.a2, 0.99007225036621, 1.0e-9));
+    }
+
+    #[test]
+    fn stereo_sine_levels() {
+        // A 1 kHz sine at -23 dBFS in both channels gives -23 LUFS
+        let mut meter = LoudnessMeter::new(48000);
+        let mut position = 0;
+        feed_sine(&mut meter, &mut position, -23.0, 5);
+        let levels = meter.get_levels();
+        assert!(is_close(levels.momentary as PrcFmt, -23.0, 0.1));
+        assert!(is_close(levels.short_term as PrcFmt, -23.0, 0.1));
+        assert!(is_close(levels.integrated as PrcFmt, -23.0, 0.1));
+        assert!(is_close(
+            levels.integrated as PrcFmt,
+            levels.momentary as PrcFmt,
+            1.0e-3
+        ));
+        assert!(is_close(levels.range as PrcFmt, 0.0, 0.2));
+    }
+
+    #[test]
+    fn gated_integrated_and_range() {
+        let mut meter = LoudnessMeter::new(48000);
+        let mut position = 0;
+        // Quiet parts more than 10 LU below are gated out of the integrated loudness
+        feed_sine(&mut meter, &mut position, -23.0, 10);
+        feed_sine(&mut meter, &mut position, -50.0, 10);
+        let levels = meter.get_levels();
+        assert!(is_close(levels.integrated as PrcFmt, -23.0, 0.2));
+        assert!(is_close(levels.momentary as PrcFmt, -50.0, 0.1));
+
+        // Two parts 10 LU apart give a range of 10 LU
+        meter.reset();
+        assert_eq!(meter.get_levels().integrated, UNDEFINED_LOUDNESS);
+        feed_sine(&mut meter, &mut position, -20.0, 20);
+        feed_sine(&mut meter, &mut position, -30.0, 20);
+        let levels = meter.get_levels();
+        assert!(is_close(levels.range as PrcFmt, 10.0, 0.5));
+    }
+}
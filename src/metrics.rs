@@ -0,0 +1,367 @@
+use std::fmt::Write as FmtWrite;
+use std::io::ErrorKind;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ProcessingState;
+use Res;
+use StatusStructs;
+
+/// Time allowed for a client to send its whole request, and to receive the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Largest accepted size of a request header.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// The processing states, in the order they are listed in the metrics.
+const STATES: [ProcessingState; 4] = [
+    ProcessingState::Running,
+    ProcessingState::Paused,
+    ProcessingState::Inactive,
+    ProcessingState::Starting,
+];
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn write_channel_values(out: &mut String, name: &str, side: &str, values: &[f32]) {
+    for (channel, value) in values.iter().enumerate() {
+        writeln!(
+            out,
+            "{}{{side=\"{}\",channel=\"{}\"}} {}",
+            name, side, channel, value
+        )
+        .unwrap();
+    }
+}
+
+/// Get the current status in the Prometheus text format.
+pub fn render_metrics(status_structs: &StatusStructs) -> String {
+    let mut out = String::new();
+    let (state, capture_rate, rate_adjust, capture_peak, capture_rms) = {
+        let capstat = status_structs.capture.read().unwrap();
+        (
+            capstat.state,
+            capstat.measured_samplerate,
+            capstat.rate_adjust,
+            capstat.signal_peak.clone(),
+            capstat.signal_rms.clone(),
+        )
+    };
+    let (buffer_level, clipped_samples, playback_peak, playback_rms) = {
+        let pbstat = status_structs.playback.read().unwrap();
+        (
+            pbstat.buffer_level,
+            pbstat.clipped_samples,
+            pbstat.signal_peak.clone(),
+            pbstat.signal_rms.clone(),
+        )
+    };
+    let load = status_structs
+        .status
+        .read()
+        .unwrap()
+        .processing_load
+        .clone();
+
+    write_header(
+        &mut out,
+        "camilladsp_state",
+        "Current processing state, 1 for the active state.",
+        "gauge",
+    );
+    for value in STATES.iter() {
+        writeln!(
+            out,
+            "camilladsp_state{{state=\"{}\"}} {}",
+            value.to_string().to_lowercase(),
+            (*value == state) as usize
+        )
+        .unwrap();
+    }
+    write_header(
+        &mut out,
+        "camilladsp_capture_rate_hz",
+        "Measured sample rate of the capture device.",
+        "gauge",
+    );
+    writeln!(out, "camilladsp_capture_rate_hz {}", capture_rate).unwrap();
+    write_header(
+        &mut out,
+        "camilladsp_rate_adjust",
+        "Adjustment factor applied to the capture rate.",
+        "gauge",
+    );
+    writeln!(out, "camilladsp_rate_adjust {}", rate_adjust).unwrap();
+    write_header(
+        &mut out,
+        "camilladsp_buffer_level_frames",
+        "Buffer level of the playback device.",
+        "gauge",
+    );
+    writeln!(out, "camilladsp_buffer_level_frames {}", buffer_level).unwrap();
+    write_header(
+        &mut out,
+        "camilladsp_clipped_samples_total",
+        "Number of clipped samples since the config was loaded.",
+        "counter",
+    );
+    writeln!(out, "camilladsp_clipped_samples_total {}", clipped_samples).unwrap();
+    write_header(
+        &mut out,
+        "camilladsp_processing_load_percent",
+        "Time spent processing each chunk, in percent of the chunk duration.",
+        "gauge",
+    );
+    for (stat, value) in [
+        ("min", load.min),
+        ("average", load.average),
+        ("max", load.max),
+    ]
+    .iter()
+    {
+        writeln!(
+            out,
+            "camilladsp_processing_load_percent{{stat=\"{}\"}} {}",
+            stat, value
+        )
+        .unwrap();
+    }
+    write_header(
+        &mut out,
+        "camilladsp_signal_peak_dbfs",
+        "Peak level of each channel in the last chunk.",
+        "gauge",
+    );
+    write_channel_values(
+        &mut out,
+        "camilladsp_signal_peak_dbfs",
+        "capture",
+        &capture_peak,
+    );
+    write_channel_values(
+        &mut out,
+        "camilladsp_signal_peak_dbfs",
+        "playback",
+        &playback_peak,
+    );
+    write_header(
+        &mut out,
+        "camilladsp_signal_rms_dbfs",
+        "RMS level of each channel in the last chunk.",
+        "gauge",
+    );
+    write_channel_values(
+        &mut out,
+        "camilladsp_signal_rms_dbfs",
+        "capture",
+        &capture_rms,
+    );
+    write_channel_values(
+        &mut out,
+        "camilladsp_signal_rms_dbfs",
+        "playback",
+        &playback_rms,
+    );
+    out
+}
+
+/// Get the method and path from the request line of a HTTP request.
+fn parse_request(request: &str) -> Option<(&str, &str)> {
+    let mut parts = request.lines().next()?.split_whitespace();
+    let method = parts.next()?;
+    let path = parts.next()?;
+    Some((method, path))
+}
+
+/// Read a request and reply with the metrics, or with an error for anything else than `GET /metrics`.
+fn handle_connection(mut stream: TcpStream, status_structs: &StatusStructs) -> Res<()> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        // The timeout applies to the whole request, not to each read
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(std::io::Error::new(ErrorKind::TimedOut, "Request timed out").into());
+        }
+        stream.set_read_timeout(Some(deadline - now))?;
+        let nbr = stream.read(&mut buffer)?;
+        if nbr == 0 || request.len() + nbr > MAX_REQUEST_SIZE {
+            break;
+        }
+        request.extend_from_slice(&buffer[0..nbr]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let (status, body) = match parse_request(&request) {
+        Some(("GET", "/metrics")) => ("200 OK", render_metrics(status_structs)),
+        Some(("GET", _)) => ("404 Not Found", "Not found\n".to_string()),
+        Some(_) => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+        None => ("400 Bad Request", "Bad request\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Start a HTTP server that serves the metrics at `/metrics`, for scraping by Prometheus.
+pub fn start_metrics_server(address: &str, port: usize, status_structs: StatusStructs) {
+    let address = address.to_string();
+    debug!("Start metrics server on {}:{}", address, port);
+    thread::Builder::new()
+        .name("MetricsServer".to_string())
+        .spawn(
+            move || match TcpListener::bind(format!("{}:{}", address, port)) {
+                Ok(server) => {
+                    for stream in server.incoming() {
+                        match stream {
+                            Ok(stream) => {
+                                // Handle each connection in its own thread, so that a slow client can't block others
+                                let status_structs = status_structs.clone();
+                                let spawned = thread::Builder::new()
+                                    .name("MetricsClient".to_string())
+                                    .spawn(move || {
+                                        if let Err(err) = handle_connection(stream, &status_structs)
+                                        {
+                                            debug!("Metrics request failed: {}", err);
+                                        }
+                                    });
+                                if let Err(err) = spawned {
+                                    error!("Failed to start metrics client thread: {}", err);
+                                }
+                            }
+                            Err(err) => debug!("Metrics connection failed: {}", err),
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("Failed to start metrics server: {}", err);
+                }
+            },
+        )
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use loudnessmeter::LoudnessLevels;
+    use metrics::{parse_request, render_metrics, start_metrics_server};
+    use spectrum::Spectrum;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use {
+        CaptureStatus, PlaybackStatus, ProcessingLoad, ProcessingParameters, ProcessingState,
+        ProcessingStatus, StatusStructs, StopReason,
+    };
+
+    fn status_structs() -> StatusStructs {
+        StatusStructs {
+            capture: Arc::new(RwLock::new(CaptureStatus {
+                measured_samplerate: 44098,
+                update_interval: 1000,
+                signal_range: 0.0,
+                rate_adjust: 1.001,
+                state: ProcessingState::Running,
+                signal_rms: vec![-20.0, -21.5],
+                signal_peak: vec![-17.0, -18.5],
+                used_channels: vec![true, true],
+                loudness: LoudnessLevels::default(),
+            })),
+            playback: Arc::new(RwLock::new(PlaybackStatus {
+                buffer_level: 1024,
+                clipped_samples: 3,
+                update_interval: 1000,
+                signal_rms: vec![-12.0],
+                signal_peak: vec![-9.0],
+                loudness: LoudnessLevels::default(),
+            })),
+            processing: Arc::new(RwLock::new(ProcessingParameters {
+                volume: 0.0,
+                mute: false,
+                faders: Default::default(),
+            })),
+            status: Arc::new(RwLock::new(ProcessingStatus {
+                stop_reason: StopReason::None,
+                processing_load: ProcessingLoad {
+                    min: 5.0,
+                    average: 10.0,
+                    max: 15.5,
+                },
+                capture_spectrum: Spectrum::default(),
+                playback_spectrum: Spectrum::default(),
+                reset_loudness: false,
+            })),
+        }
+    }
+
+    #[test]
+    fn metrics_text() {
+        let text = render_metrics(&status_structs());
+        let lines = text.lines().collect::<Vec<&str>>();
+        for expected in [
+            "camilladsp_state{state=\"running\"} 1",
+            "camilladsp_state{state=\"paused\"} 0",
+            "camilladsp_capture_rate_hz 44098",
+            "camilladsp_rate_adjust 1.001",
+            "camilladsp_buffer_level_frames 1024",
+            "camilladsp_clipped_samples_total 3",
+            "# TYPE camilladsp_clipped_samples_total counter",
+            "camilladsp_processing_load_percent{stat=\"max\"} 15.5",
+            "camilladsp_signal_peak_dbfs{side=\"capture\",channel=\"1\"} -18.5",
+            "camilladsp_signal_rms_dbfs{side=\"playback\",channel=\"0\"} -12",
+        ]
+        .iter()
+        {
+            assert!(lines.contains(expected), "missing line: {}", expected);
+        }
+    }
+
+    #[test]
+    fn parse_request_line() {
+        assert_eq!(
+            parse_request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(parse_request(""), None);
+        assert_eq!(parse_request("GET\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn slow_client_does_not_block() {
+        start_metrics_server("127.0.0.1", 19187, status_structs());
+        let mut slow = None;
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect("127.0.0.1:19187") {
+                slow = Some(stream);
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        // send only part of a request, and leave the connection open
+        let mut slow = slow.expect("metrics server did not start");
+        slow.write_all(b"GET /metr").unwrap();
+        let start = Instant::now();
+        let mut client = TcpStream::connect("127.0.0.1:19187").unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
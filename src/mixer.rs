@@ -9,6 +9,8 @@ pub struct Mixer {
     pub channels_in: usize,
     pub channels_out: usize,
     pub mapping: Vec<Vec<MixerSource>>,
+    // mapping used before the last parameter update, to ramp from during the next chunk
+    prev_mapping: Option<Vec<Vec<MixerSource>>>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -17,83 +19,120 @@ pub struct MixerSource {
     pub gain: PrcFmt,
 }
 
+/// Get the sources of each output channel, skipping muted sources and destinations.
+fn mapping_from_config(config: config::Mixer) -> Vec<Vec<MixerSource>> {
+    let mut mapping = vec![Vec::<MixerSource>::new(); config.channels.out];
+    for cfg_mapping in config.mapping {
+        if !cfg_mapping.mute {
+            let dest = cfg_mapping.dest;
+            for cfg_src in cfg_mapping.sources {
+                if !cfg_src.mute {
+                    let mut gain: PrcFmt = 10.0;
+                    gain = gain.powf(cfg_src.gain / 20.0);
+                    if cfg_src.inverted {
+                        gain = -gain;
+                    }
+                    let src = MixerSource {
+                        channel: cfg_src.channel,
+                        gain,
+                    };
+                    mapping[dest].push(src);
+                }
+            }
+        }
+    }
+    mapping
+}
+
+/// Mix the waveforms of an AudioChunk according to a mapping.
+fn mix(mapping: &[Vec<MixerSource>], input: &AudioChunk) -> Vec<Vec<PrcFmt>> {
+    let mut waveforms = Vec::<Vec<PrcFmt>>::with_capacity(mapping.len());
+    for sources in mapping.iter() {
+        let mut waveform = vec![0.0; input.frames];
+        for source in sources.iter() {
+            let source_wf = &input.waveforms[source.channel];
+            if !source_wf.is_empty() {
+                for (out, value) in waveform.iter_mut().zip(source_wf.iter()) {
+                    *out += source.gain * value;
+                }
+            }
+        }
+        waveforms.push(waveform);
+    }
+    waveforms
+}
+
 impl Mixer {
     /// Creates a Mixer from a config struct
     pub fn from_config(name: String, config: config::Mixer) -> Self {
         let ch_in = config.channels.r#in;
         let ch_out = config.channels.out;
-        let mut mapping = vec![Vec::<MixerSource>::new(); ch_out];
-        for cfg_mapping in config.mapping {
-            if !cfg_mapping.mute {
-                let dest = cfg_mapping.dest;
-                for cfg_src in cfg_mapping.sources {
-                    if !cfg_src.mute {
-                        let mut gain: PrcFmt = 10.0;
-                        gain = gain.powf(cfg_src.gain / 20.0);
-                        if cfg_src.inverted {
-                            gain = -gain;
-                        }
-                        let src = MixerSource {
-                            channel: cfg_src.channel,
-                            gain,
-                        };
-                        mapping[dest].push(src);
-                    }
-                }
-            }
-        }
+        let mapping = mapping_from_config(config);
         Mixer {
             name,
             channels_in: ch_in,
             channels_out: ch_out,
             mapping,
+            prev_mapping: None,
         }
     }
 
+    /// Update the gains, mute and inversion of the sources.
+    /// The number of channels must stay the same, see `is_same_topology`.
+    /// The change is ramped in over the next chunk.
     pub fn update_parameters(&mut self, config: config::Mixer) {
-        let ch_in = config.channels.r#in;
-        let ch_out = config.channels.out;
-        let mut mapping = vec![Vec::<MixerSource>::new(); ch_out];
-        for cfg_mapping in config.mapping {
-            let dest = cfg_mapping.dest;
-            for cfg_src in cfg_mapping.sources {
-                let mut gain: PrcFmt = 10.0;
-                gain = gain.powf(cfg_src.gain / 20.0);
-                if cfg_src.inverted {
-                    gain = -gain;
-                }
-                let src = MixerSource {
-                    channel: cfg_src.channel,
-                    gain,
-                };
-                mapping[dest].push(src);
-            }
+        let mapping = mapping_from_config(config);
+        let prev_mapping = std::mem::replace(&mut self.mapping, mapping);
+        // if several updates arrive between two chunks, ramp from the one that was last used
+        if self.prev_mapping.is_none() {
+            self.prev_mapping = Some(prev_mapping);
         }
-        self.channels_in = ch_in;
-        self.channels_out = ch_out;
-        self.mapping = mapping;
+        debug!("Updated mixer '{}'", self.name);
     }
 
     /// Apply a Mixer to an AudioChunk, yielding a new AudioChunk with a possibly different number of channels.
     pub fn process_chunk(&mut self, input: &AudioChunk) -> AudioChunk {
-        let mut waveforms = Vec::<Vec<PrcFmt>>::with_capacity(self.channels_out);
-        for out_chan in 0..self.channels_out {
-            waveforms.push(vec![0.0; input.frames]);
-            for source in 0..self.mapping[out_chan].len() {
-                let source_chan = self.mapping[out_chan][source].channel;
-                if !input.waveforms[source_chan].is_empty() {
-                    let gain = self.mapping[out_chan][source].gain;
-                    for n in 0..input.frames {
-                        waveforms[out_chan][n] += gain * input.waveforms[source_chan][n];
-                    }
+        let mut waveforms = mix(&self.mapping, input);
+        if let Some(prev_mapping) = self.prev_mapping.take() {
+            // linear ramp from the previous to the new gains
+            let prev_waveforms = mix(&prev_mapping, input);
+            let frames = input.frames as PrcFmt;
+            for (waveform, prev_waveform) in waveforms.iter_mut().zip(prev_waveforms.iter()) {
+                for (n, (value, prev_value)) in
+                    waveform.iter_mut().zip(prev_waveform.iter()).enumerate()
+                {
+                    let ramp = (n + 1) as PrcFmt / frames;
+                    *value = ramp * *value + (1.0 - ramp) * prev_value;
                 }
             }
         }
-
         AudioChunk::from(input, waveforms)
     }
 }
 
+/// Check if two mixer configs have the same channels and connect the same sources to each destination.
+/// Mixers with the same topology only differ in gains, mute and inversion,
+/// and can be updated without rebuilding the pipeline.
+pub fn is_same_topology(current: &config::Mixer, new: &config::Mixer) -> bool {
+    let connections = |mixer: &config::Mixer| {
+        mixer
+            .mapping
+            .iter()
+            .map(|mapping| {
+                (
+                    mapping.dest,
+                    mapping
+                        .sources
+                        .iter()
+                        .map(|source| source.channel)
+                        .collect::<Vec<usize>>(),
+                )
+            })
+            .collect::<Vec<(usize, Vec<usize>)>>()
+    };
+    current.channels == new.channels && connections(current) == connections(new)
+}
+
 /// Validate the mixer config, to give a helpful message intead of a panic.
 pub fn validate_mixer(mixer_config: &config::Mixer) -> Res<()> {
     let chan_in = mixer_config.channels.r#in;
@@ -139,9 +178,11 @@ pub fn get_used_input_channels(mixer_config: &config::Mixer) -> Vec<bool> {
 
 #[cfg(test)]
 mod tests {
+    use audiodevice::AudioChunk;
     use config::{Mixer, MixerChannels, MixerMapping, MixerSource};
     use mixer;
-    use mixer::get_used_input_channels;
+    use mixer::{get_used_input_channels, is_same_topology};
+    use PrcFmt;
 
     #[test]
     fn check_all_used() {
@@ -515,4 +556,50 @@ mod tests {
 
         assert_eq!(mix.mapping, exp_map);
     }
+
+    fn make_stereo_mixer(gain: PrcFmt, mute: bool) -> Mixer {
+        let mapping = (0..2)
+            .map(|ch| MixerMapping {
+                dest: ch,
+                sources: vec![MixerSource {
+                    channel: ch,
+                    gain,
+                    inverted: false,
+                    mute: mute && ch == 1,
+                }],
+                mute: false,
+            })
+            .collect();
+        Mixer {
+            channels: MixerChannels { r#in: 2, out: 2 },
+            mapping,
+        }
+    }
+
+    #[test]
+    fn check_topology() {
+        let conf = make_stereo_mixer(0.0, false);
+        assert!(is_same_topology(&conf, &make_stereo_mixer(-6.0, true)));
+        let mut swapped = make_stereo_mixer(0.0, false);
+        swapped.mapping[0].sources[0].channel = 1;
+        assert!(!is_same_topology(&conf, &swapped));
+        let mut wider = make_stereo_mixer(0.0, false);
+        wider.channels.out = 3;
+        assert!(!is_same_topology(&conf, &wider));
+    }
+
+    #[test]
+    fn ramp_after_update() {
+        let mut mix = mixer::Mixer::from_config("dummy".to_string(), make_stereo_mixer(0.0, false));
+        mix.update_parameters(make_stereo_mixer(-120.0, true));
+        let chunk = AudioChunk::new(vec![vec![1.0; 4], vec![1.0; 4]], 1.0, 1.0, 4, 4);
+        let output = mix.process_chunk(&chunk);
+        // the muted channel ramps down to zero over the chunk
+        assert_eq!(output.waveforms[1], vec![0.75, 0.5, 0.25, 0.0]);
+        assert!(output.waveforms[0][0] > output.waveforms[0][3]);
+        assert!(output.waveforms[0][3] < 1.0e-5);
+        // the next chunk uses the new gains only
+        let output = mix.process_chunk(&chunk);
+        assert_eq!(output.waveforms[1], vec![0.0; 4]);
+    }
 }
@@ -0,0 +1,319 @@
+use crate::filters::Filter;
+use config;
+use filters;
+use helpers::{multiply_add_elements, multiply_elements};
+use num_complex::Complex;
+use num_traits::Zero;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use std::sync::Arc;
+
+// Sample format
+use PrcFmt;
+use Res;
+
+/// Largest partition size, unless the chunksize is larger.
+const MAX_PARTITION_SIZE: usize = 16384;
+
+/// Number of segments of each partition size, before moving on to the next size.
+const SEGMENTS_PER_SIZE: usize = 2;
+
+/// A uniformly partitioned convolution of one part of the filter,
+/// starting `offset` samples into the impulse response.
+/// The stage collects input until a full block is available, and then adds
+/// the result to the shared output buffer. Since the offset is at least as long as
+/// a block minus a chunk, the result is always ready before it is needed.
+struct Stage {
+    blocksize: usize,
+    offset: usize,
+    nsegments: usize,
+    coeffs_f: Vec<Vec<Complex<PrcFmt>>>,
+    fft: Arc<dyn RealToComplex<PrcFmt>>,
+    ifft: Arc<dyn ComplexToReal<PrcFmt>>,
+    scratch_fw: Vec<Complex<PrcFmt>>,
+    scratch_inv: Vec<Complex<PrcFmt>>,
+    input_buf: Vec<PrcFmt>,
+    input_fill: usize,
+    input_f: Vec<Vec<Complex<PrcFmt>>>,
+    temp_buf: Vec<Complex<PrcFmt>>,
+    output_buf: Vec<PrcFmt>,
+    index: usize,
+}
+
+impl Stage {
+    fn new(
+        blocksize: usize,
+        offset: usize,
+        nsegments: usize,
+        coeffs: &[PrcFmt],
+        planner: &mut RealFftPlanner<PrcFmt>,
+    ) -> Self {
+        let fft = planner.plan_fft_forward(2 * blocksize);
+        let ifft = planner.plan_fft_inverse(2 * blocksize);
+        let scratch_fw = fft.make_scratch_vec();
+        let scratch_inv = ifft.make_scratch_vec();
+        let mut stage = Stage {
+            blocksize,
+            offset,
+            nsegments,
+            coeffs_f: Vec::new(),
+            fft,
+            ifft,
+            scratch_fw,
+            scratch_inv,
+            input_buf: vec![0.0; 2 * blocksize],
+            input_fill: 0,
+            input_f: vec![vec![Complex::zero(); blocksize + 1]; nsegments],
+            temp_buf: vec![Complex::zero(); blocksize + 1],
+            output_buf: vec![0.0; 2 * blocksize],
+            index: 0,
+        };
+        stage.set_coeffs(coeffs);
+        stage
+    }
+
+    /// Transform the part of the impulse response handled by this stage.
+    fn set_coeffs(&mut self, coeffs: &[PrcFmt]) {
+        let mut coeffs_padded = vec![vec![0.0; 2 * self.blocksize]; self.nsegments];
+        let mut coeffs_f = vec![vec![Complex::zero(); self.blocksize + 1]; self.nsegments];
+        for (n, coeff) in coeffs
+            .iter()
+            .skip(self.offset)
+            .take(self.nsegments * self.blocksize)
+            .enumerate()
+        {
+            coeffs_padded[n / self.blocksize][n % self.blocksize] =
+                coeff / (2 * self.blocksize) as PrcFmt;
+        }
+        for (segment, segment_f) in coeffs_padded.iter_mut().zip(coeffs_f.iter_mut()) {
+            self.fft
+                .process_with_scratch(segment, segment_f, &mut self.scratch_fw)
+                .unwrap();
+        }
+        self.coeffs_f = coeffs_f;
+    }
+
+    /// Add a chunk of input, and when a full block has been collected,
+    /// add the convolved block to the output buffer.
+    /// `position` is the index in the output buffer of the first sample of the chunk.
+    fn process(&mut self, waveform: &[PrcFmt], output: &mut [PrcFmt], position: usize) {
+        let chunksize = waveform.len();
+        self.input_buf[self.input_fill..self.input_fill + chunksize].copy_from_slice(waveform);
+        self.input_fill += chunksize;
+        if self.input_fill < self.blocksize {
+            return;
+        }
+        self.input_fill = 0;
+        for item in self.input_buf.iter_mut().skip(self.blocksize) {
+            *item = 0.0;
+        }
+
+        // FFT and store result in history, update index
+        self.index = (self.index + 1) % self.nsegments;
+        self.fft
+            .process_with_scratch(
+                &mut self.input_buf,
+                &mut self.input_f[self.index],
+                &mut self.scratch_fw,
+            )
+            .unwrap();
+
+        // Loop through history of input FTs, multiply with filter FTs, accumulate result
+        multiply_elements(
+            &mut self.temp_buf,
+            &self.input_f[self.index],
+            &self.coeffs_f[0],
+        );
+        for segm in 1..self.nsegments {
+            let hist_idx = (self.index + self.nsegments - segm) % self.nsegments;
+            multiply_add_elements(
+                &mut self.temp_buf,
+                &self.input_f[hist_idx],
+                &self.coeffs_f[segm],
+            );
+        }
+        self.ifft
+            .process_with_scratch(
+                &mut self.temp_buf,
+                &mut self.output_buf,
+                &mut self.scratch_inv,
+            )
+            .unwrap();
+
+        // The block started `blocksize - chunksize` samples before the current chunk,
+        // and its response starts `offset` samples after that.
+        let start = position + chunksize + self.offset - self.blocksize;
+        let buflen = output.len();
+        for (n, value) in self.output_buf.iter().enumerate() {
+            output[(start + n) % buflen] += value;
+        }
+    }
+}
+
+/// Get the partition sizes and the number of segments of each size,
+/// for a filter of `length` samples processed in chunks of `chunksize`.
+/// The partitions start at the chunksize, and double in size
+/// after every `SEGMENTS_PER_SIZE` segments, as long as they fit within `MAX_PARTITION_SIZE`.
+/// The last size is then used for the rest of the filter.
+pub fn partition_layout(chunksize: usize, length: usize) -> Vec<(usize, usize)> {
+    let max_size = MAX_PARTITION_SIZE.max(chunksize);
+    let mut layout = Vec::new();
+    let mut covered = 0;
+    let mut size = chunksize;
+    while covered < length || layout.is_empty() {
+        let needed = (length.saturating_sub(covered) + size - 1) / size;
+        let nsegments = if 2 * size > max_size {
+            needed.max(1)
+        } else {
+            needed.max(1).min(SEGMENTS_PER_SIZE)
+        };
+        layout.push((size, nsegments));
+        covered += size * nsegments;
+        if 2 * size <= max_size {
+            size *= 2;
+        }
+    }
+    layout
+}
+
+/// Convolution filter using non-uniform partitions. The first partitions are as long as the chunksize,
+/// giving the same latency as the uniformly partitioned convolution,
+/// while the later parts of the impulse response are processed using longer partitions for lower CPU load.
+pub struct NonUniformConv {
+    name: String,
+    chunksize: usize,
+    layout: Vec<(usize, usize)>,
+    stages: Vec<Stage>,
+    output: Vec<PrcFmt>,
+    position: usize,
+}
+
+impl NonUniformConv {
+    /// Create a new non-uniformly partitioned convolution filter.
+    pub fn new(name: String, data_length: usize, coeffs: &[PrcFmt]) -> Self {
+        let layout = partition_layout(data_length, coeffs.len());
+        debug!("Conv {} is using non-uniform partitions {:?}", name, layout);
+        let mut planner = RealFftPlanner::<PrcFmt>::new();
+        let mut stages = Vec::with_capacity(layout.len());
+        let mut offset = 0;
+        for (blocksize, nsegments) in layout.iter() {
+            stages.push(Stage::new(
+                *blocksize,
+                offset,
+                *nsegments,
+                coeffs,
+                &mut planner,
+            ));
+            offset += blocksize * nsegments;
+        }
+        let buflen = stages
+            .iter()
+            .map(|stage| stage.offset + stage.blocksize + data_length)
+            .max()
+            .unwrap();
+        NonUniformConv {
+            name,
+            chunksize: data_length,
+            layout,
+            stages,
+            output: vec![0.0; buflen],
+            position: 0,
+        }
+    }
+
+    pub fn from_config(name: String, data_length: usize, conf: config::ConvParameters) -> Self {
+        let values = filters::read_conv_coefficients(&conf).unwrap();
+        NonUniformConv::new(name, data_length, &values)
+    }
+}
+
+impl Filter for NonUniformConv {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Process a waveform by letting each stage add its result to the output buffer,
+    /// and then reading the output for the current chunk.
+    fn process_waveform(&mut self, waveform: &mut Vec<PrcFmt>) -> Res<()> {
+        for stage in self.stages.iter_mut() {
+            stage.process(waveform, &mut self.output, self.position);
+        }
+        for (item, value) in waveform
+            .iter_mut()
+            .zip(self.output[self.position..self.position + self.chunksize].iter_mut())
+        {
+            *item = *value;
+            *value = 0.0;
+        }
+        self.position = (self.position + self.chunksize) % self.output.len();
+        Ok(())
+    }
+
+    fn update_parameters(&mut self, conf: config::Filter) {
+        if let config::Filter::Conv {
+            parameters: conf, ..
+        } = conf
+        {
+            let coeffs = filters::read_conv_coefficients(&conf).unwrap();
+            let layout = partition_layout(self.chunksize, coeffs.len());
+            if layout == self.layout {
+                // Same partitions, lets keep history
+                for stage in self.stages.iter_mut() {
+                    stage.set_coeffs(&coeffs);
+                }
+            } else {
+                // Partitions changed, start over with cleared history
+                *self = NonUniformConv::new(self.name.clone(), self.chunksize, &coeffs);
+            }
+        } else {
+            // This should never happen unless there is a bug somewhere else
+            panic!("Invalid config change!");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PrcFmt;
+    use filters::Filter;
+    use nonuniformconv::{partition_layout, NonUniformConv};
+
+    fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    #[test]
+    fn check_layout() {
+        assert_eq!(partition_layout(8, 5), vec![(8, 1)]);
+        assert_eq!(partition_layout(8, 40), vec![(8, 2), (16, 2)]);
+        assert_eq!(
+            partition_layout(4096, 100000),
+            vec![(4096, 2), (8192, 2), (16384, 5)]
+        );
+        assert_eq!(partition_layout(32768, 100000), vec![(32768, 4)]);
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        let coeffs = (0..200)
+            .map(|n| ((n as PrcFmt) * 0.37).sin() / (1.0 + n as PrcFmt))
+            .collect::<Vec<PrcFmt>>();
+        let input = (0..400)
+            .map(|n| ((n as PrcFmt) * 1.3).cos())
+            .collect::<Vec<PrcFmt>>();
+        let mut expected = vec![0.0; input.len()];
+        for (n, value) in expected.iter_mut().enumerate() {
+            for (m, coeff) in coeffs.iter().enumerate().take(n + 1) {
+                *value += coeff * input[n - m];
+            }
+        }
+        let mut filter = NonUniformConv::new("test".to_string(), 8, &coeffs);
+        for (chunk_in, chunk_exp) in input.chunks(8).zip(expected.chunks(8)) {
+            let mut waveform = chunk_in.to_vec();
+            filter.process_waveform(&mut waveform).unwrap();
+            for (value, exp) in waveform.iter().zip(chunk_exp.iter()) {
+                assert!(is_close(*value, *exp, 1.0e-6));
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use audiodevice::*;
+use countertimer;
+use std::sync::mpsc;
+use std::sync::{Arc, Barrier, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::PlaybackStatus;
+use Res;
+use StatusMessage;
+
+/// A playback device that discards all audio.
+/// It simulates a sound card with a buffer that is emptied at the sample rate,
+/// or consumes the chunks as fast as possible when unthrottled.
+pub struct NullPlaybackDevice {
+    pub chunksize: usize,
+    pub samplerate: usize,
+    pub channels: usize,
+    pub unthrottled: bool,
+    pub target_level: usize,
+    pub adjust_period: f32,
+    pub enable_rate_adjust: bool,
+}
+
+/// A virtual playback buffer, that is played at the sample rate.
+struct VirtualBuffer {
+    samplerate: usize,
+    start: Instant,
+    frames_written: usize,
+}
+
+impl VirtualBuffer {
+    fn new(samplerate: usize) -> Self {
+        VirtualBuffer {
+            samplerate,
+            start: Instant::now(),
+            frames_written: 0,
+        }
+    }
+
+    /// Number of written frames that have not yet been played, negative on underrun.
+    fn level(&self) -> isize {
+        let played = (self.start.elapsed().as_secs_f64() * self.samplerate as f64) as isize;
+        self.frames_written as isize - played
+    }
+
+    /// Wait until the buffer level has dropped to the target level, and then add the new frames.
+    fn write(&mut self, frames: usize, target_level: usize) {
+        let level = self.level();
+        if level < 0 {
+            if self.frames_written > 0 {
+                warn!("Null playback buffer underrun");
+            }
+            self.start = Instant::now();
+            self.frames_written = 0;
+        } else if level as usize > target_level {
+            let wait = (level as usize - target_level) as f64 / self.samplerate as f64;
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+        self.frames_written += frames;
+    }
+}
+
+/// Count the samples of the valid frames of a chunk that would be clipped when converted to integers.
+fn count_clipped(chunk: &AudioChunk) -> usize {
+    chunk
+        .waveforms
+        .iter()
+        .map(|wf| {
+            wf.iter()
+                .take(chunk.valid_frames)
+                .filter(|value| value.abs() > 1.0)
+                .count()
+        })
+        .sum()
+}
+
+/// Start a playback thread listening for AudioMessages via a channel.
+impl PlaybackDevice for NullPlaybackDevice {
+    fn start(
+        &mut self,
+        channel: mpsc::Receiver<AudioMessage>,
+        barrier: Arc<Barrier>,
+        status_channel: mpsc::Sender<StatusMessage>,
+        playback_status: Arc<RwLock<PlaybackStatus>>,
+    ) -> Res<Box<thread::JoinHandle<()>>> {
+        let samplerate = self.samplerate;
+        let unthrottled = self.unthrottled;
+        let target_level = if self.target_level > 0 {
+            self.target_level
+        } else {
+            self.chunksize
+        };
+        let adjust = self.adjust_period > 0.0 && self.enable_rate_adjust && !unthrottled;
+        let adjust_period = self.adjust_period;
+        let handle = thread::Builder::new()
+            .name("NullPlayback".to_string())
+            .spawn(move || {
+                status_channel
+                    .send(StatusMessage::PlaybackReady)
+                    .unwrap_or(());
+                barrier.wait();
+                debug!("starting playback loop");
+                let mut buffer = VirtualBuffer::new(samplerate);
+                let mut timer = countertimer::Stopwatch::new();
+                let mut buffer_avg = countertimer::Averager::new();
+                loop {
+                    match channel.recv() {
+                        Ok(AudioMessage::Audio(chunk)) => {
+                            if !unthrottled {
+                                buffer.write(chunk.valid_frames, target_level);
+                                buffer_avg.add_value(buffer.level().max(0) as f64);
+                            }
+                            let nbr_clipped = count_clipped(&chunk);
+                            if nbr_clipped > 0 {
+                                playback_status.write().unwrap().clipped_samples += nbr_clipped;
+                            }
+                            if timer.larger_than_millis((1000.0 * adjust_period) as u64) {
+                                if let Some(av_delay) = buffer_avg.get_average() {
+                                    timer.restart();
+                                    buffer_avg.restart();
+                                    if adjust {
+                                        let speed = calculate_speed(
+                                            av_delay,
+                                            target_level,
+                                            adjust_period,
+                                            samplerate as u32,
+                                        );
+                                        status_channel
+                                            .send(StatusMessage::SetSpeed(speed))
+                                            .unwrap_or(());
+                                    }
+                                    playback_status.write().unwrap().buffer_level =
+                                        av_delay as usize;
+                                }
+                            }
+                            let chunk_stats = chunk.get_stats();
+                            let mut pb_stat = playback_status.write().unwrap();
+                            pb_stat.signal_rms = chunk_stats.rms_db();
+                            pb_stat.signal_peak = chunk_stats.peak_db();
+                        }
+                        Ok(AudioMessage::EndOfStream) => {
+                            status_channel
+                                .send(StatusMessage::PlaybackDone)
+                                .unwrap_or(());
+                            break;
+                        }
+                        Err(err) => {
+                            error!("Message channel error: {}", err);
+                            status_channel
+                                .send(StatusMessage::PlaybackError(err.to_string()))
+                                .unwrap_or(());
+                            break;
+                        }
+                    }
+                }
+            })
+            .unwrap();
+        Ok(Box::new(handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use audiodevice::AudioChunk;
+    use nulldevice::{count_clipped, VirtualBuffer};
+    use std::time::Instant;
+
+    #[test]
+    fn clipped_samples() {
+        let chunk = AudioChunk::new(
+            vec![vec![0.5, 1.5, -2.0, 1.5], vec![1.0, -1.0, 0.0, 3.0]],
+            3.0,
+            -2.0,
+            4,
+            3,
+        );
+        // the last frame is not valid
+        assert_eq!(count_clipped(&chunk), 2);
+    }
+
+    #[test]
+    fn realtime_pacing() {
+        let mut buffer = VirtualBuffer::new(1000);
+        let start = Instant::now();
+        for _ in 0..5 {
+            buffer.write(10, 10);
+        }
+        // the first chunk fills the buffer, and then it waits for 10 ms per chunk
+        let elapsed = start.elapsed().as_millis();
+        assert!((25..200).contains(&elapsed));
+        assert!(buffer.level() <= 20);
+    }
+}
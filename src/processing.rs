@@ -1,10 +1,176 @@
 use audiodevice::*;
 use config;
+use countertimer;
 use filters;
+use loudnessmeter::{LoudnessLevels, LoudnessMeter};
+use mixer;
+use spectrum::{Spectrum, SpectrumAnalyzer};
+use std::mem;
 use std::sync::mpsc;
-use std::sync::{Arc, Barrier, RwLock};
+use std::sync::{Arc, Barrier};
 use std::thread;
-use ProcessingParameters;
+use std::time::Instant;
+use PrcFmt;
+use ProcessingLoad;
+use StatusStructs;
+
+/// A pipeline, that may be crossfading from the output of the one it replaced.
+struct CrossfadingPipeline {
+    pipeline: filters::Pipeline,
+    config: config::Configuration,
+    fade: Option<Crossfade>,
+}
+
+/// A running crossfade. The replaced pipeline may itself still be crossfading
+/// from an older one, so that a new fade continues from the already blended output.
+struct Crossfade {
+    old: Box<CrossfadingPipeline>,
+    position: usize,
+    length: usize,
+}
+
+/// Check if a filter can be updated with new parameters, without rebuilding it.
+fn is_same_filter_kind(current: &config::Filter, new: &config::Filter) -> bool {
+    match (current, new) {
+        (
+            config::Filter::Conv {
+                partitioning: current_partitioning,
+                ..
+            },
+            config::Filter::Conv { partitioning, .. },
+        ) => current_partitioning == partitioning,
+        _ => mem::discriminant(current) == mem::discriminant(new),
+    }
+}
+
+impl CrossfadingPipeline {
+    fn new(pipeline: filters::Pipeline, config: config::Configuration) -> Self {
+        CrossfadingPipeline {
+            pipeline,
+            config,
+            fade: None,
+        }
+    }
+
+    /// Replace the pipeline, crossfading from the current output over `fade_length` frames.
+    fn replace(
+        &mut self,
+        pipeline: filters::Pipeline,
+        config: config::Configuration,
+        fade_length: usize,
+    ) {
+        let old = mem::replace(self, CrossfadingPipeline::new(pipeline, config));
+        if fade_length > 0 {
+            // Keep running the old pipeline, including any fade in progress, until it has been faded out.
+            self.fade = Some(Crossfade {
+                old: Box::new(old),
+                position: 0,
+                length: fade_length,
+            });
+        }
+    }
+
+    /// Update the parameters of filters, mixers and processors, also in the pipelines that are being faded out.
+    /// A pipeline being faded out only gets the changes that fit the config it was built from.
+    fn update_parameters(
+        &mut self,
+        new_config: &config::Configuration,
+        filters: &[String],
+        mixers: &[String],
+        processors: &[String],
+    ) {
+        let current = &self.config;
+        let filters = filters
+            .iter()
+            .filter(
+                |name| match (current.filters.get(*name), new_config.filters.get(*name)) {
+                    (Some(cur), Some(new)) => is_same_filter_kind(cur, new),
+                    _ => false,
+                },
+            )
+            .cloned()
+            .collect::<Vec<String>>();
+        let mixers = mixers
+            .iter()
+            .filter(
+                |name| match (current.mixers.get(*name), new_config.mixers.get(*name)) {
+                    (Some(cur), Some(new)) => mixer::is_same_topology(cur, new),
+                    _ => false,
+                },
+            )
+            .cloned()
+            .collect::<Vec<String>>();
+        let processors = processors
+            .iter()
+            .filter(|name| {
+                match (
+                    current.processors.get(*name),
+                    new_config.processors.get(*name),
+                ) {
+                    (Some(cur), Some(new)) => mem::discriminant(cur) == mem::discriminant(new),
+                    _ => false,
+                }
+            })
+            .cloned()
+            .collect::<Vec<String>>();
+        for name in filters.iter() {
+            self.config
+                .filters
+                .insert(name.clone(), new_config.filters[name].clone());
+        }
+        for name in mixers.iter() {
+            self.config
+                .mixers
+                .insert(name.clone(), new_config.mixers[name].clone());
+        }
+        for name in processors.iter() {
+            self.config
+                .processors
+                .insert(name.clone(), new_config.processors[name].clone());
+        }
+        if let Some(fade) = &mut self.fade {
+            fade.old
+                .update_parameters(new_config, &filters, &mixers, &processors);
+        }
+        self.pipeline
+            .update_parameters(new_config.clone(), filters, mixers, processors);
+    }
+
+    fn process_chunk(&mut self, chunk: AudioChunk) -> AudioChunk {
+        let mut done = false;
+        let chunk = if let Some(fade) = &mut self.fade {
+            let old_chunk = fade.old.process_chunk(chunk.clone());
+            let mut new_chunk = self.pipeline.process_chunk(chunk);
+            crossfade(&mut new_chunk, &old_chunk, fade.position, fade.length);
+            fade.position += new_chunk.frames;
+            done = fade.position >= fade.length;
+            new_chunk
+        } else {
+            self.pipeline.process_chunk(chunk)
+        };
+        if done {
+            debug!("Crossfade done, dropping old pipeline.");
+            self.fade = None;
+        }
+        chunk
+    }
+}
+
+/// Blend the outputs of a new and an old pipeline, using a raised cosine crossfade
+/// from the old to the new, that has reached `position` frames out of `length`.
+fn crossfade(new: &mut AudioChunk, old: &AudioChunk, position: usize, length: usize) {
+    let pi = std::f64::consts::PI as PrcFmt;
+    for (wf_new, wf_old) in new.waveforms.iter_mut().zip(old.waveforms.iter()) {
+        for (n, (val_new, val_old)) in wf_new.iter_mut().zip(wf_old.iter()).enumerate() {
+            let gain = if position + n >= length {
+                1.0
+            } else {
+                0.5 - 0.5 * (pi * (position + n) as PrcFmt / length as PrcFmt).cos()
+            };
+            *val_new = gain * *val_new + (1.0 - gain) * *val_old;
+        }
+    }
+}
 
 pub fn run_processing(
     conf_proc: config::Configuration,
@@ -12,18 +178,85 @@ pub fn run_processing(
     tx_pb: mpsc::SyncSender<AudioMessage>,
     rx_cap: mpsc::Receiver<AudioMessage>,
     rx_pipeconf: mpsc::Receiver<(config::ConfigChange, config::Configuration)>,
-    processing_status: Arc<RwLock<ProcessingParameters>>,
+    status_structs: StatusStructs,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        let mut pipeline = filters::Pipeline::from_config(conf_proc, processing_status.clone());
+        let processing_status = status_structs.processing;
+        let capture_status = status_structs.capture;
+        let playback_status = status_structs.playback;
+        let status = status_structs.status;
+        let chunk_duration =
+            conf_proc.devices.chunksize as f64 / conf_proc.devices.samplerate as f64;
+        let samplerate = conf_proc.devices.samplerate;
+        let spectrum_conf = conf_proc.devices.spectrum.clone();
+        let mut capture_analyzer = spectrum_conf
+            .as_ref()
+            .filter(|conf| conf.capture)
+            .map(|conf| SpectrumAnalyzer::new(conf, samplerate));
+        let mut playback_analyzer = spectrum_conf
+            .as_ref()
+            .filter(|conf| conf.playback)
+            .map(|conf| SpectrumAnalyzer::new(conf, samplerate));
+        let mut capture_meter = LoudnessMeter::new(samplerate);
+        let mut playback_meter = LoudnessMeter::new(samplerate);
+        let mut pipeline = CrossfadingPipeline::new(
+            filters::Pipeline::from_config(conf_proc.clone(), processing_status.clone()),
+            conf_proc,
+        );
         debug!("build filters, waiting to start processing loop");
         barrier_proc.wait();
         debug!("Processing loop starts now!");
-        loop {
+        let mut timer = countertimer::Stopwatch::new();
+        let mut load_averager = countertimer::Averager::new();
+        'processing: loop {
             match rx_cap.recv() {
                 Ok(AudioMessage::Audio(mut chunk)) => {
                     //trace!("AudioMessage::Audio received");
+                    // The meters and spectrum analyzers are not included in the processing load
+                    if capture_meter.process_chunk(&chunk) {
+                        capture_status.write().unwrap().loudness = capture_meter.get_levels();
+                    }
+                    if let Some(analyzer) = &mut capture_analyzer {
+                        if analyzer.process_chunk(&chunk) {
+                            status.write().unwrap().capture_spectrum = analyzer.get_spectrum();
+                        }
+                    }
+                    let start = Instant::now();
                     chunk = pipeline.process_chunk(chunk);
+                    // processing time in percent of the chunk duration
+                    load_averager.add_value(100.0 * start.elapsed().as_secs_f64() / chunk_duration);
+                    if playback_meter.process_chunk(&chunk) {
+                        playback_status.write().unwrap().loudness = playback_meter.get_levels();
+                        // Check for a reset request once per loudness block
+                        if status.read().unwrap().reset_loudness {
+                            debug!("Resetting integrated loudness and loudness range.");
+                            capture_meter.reset();
+                            playback_meter.reset();
+                            status.write().unwrap().reset_loudness = false;
+                        }
+                    }
+                    if let Some(analyzer) = &mut playback_analyzer {
+                        if analyzer.process_chunk(&chunk) {
+                            status.write().unwrap().playback_spectrum = analyzer.get_spectrum();
+                        }
+                    }
+                    if timer
+                        .larger_than_millis(capture_status.read().unwrap().update_interval as u64)
+                    {
+                        if let (Some(min), Some(average), Some(max)) = (
+                            load_averager.get_min(),
+                            load_averager.get_average(),
+                            load_averager.get_max(),
+                        ) {
+                            status.write().unwrap().processing_load = ProcessingLoad {
+                                min: min as f32,
+                                average: average as f32,
+                                max: max as f32,
+                            };
+                        }
+                        timer.restart();
+                        load_averager.restart();
+                    }
                     let msg = AudioMessage::Audio(chunk);
                     if tx_pb.send(msg).is_err() {
                         info!("Playback thread has already stopped.");
@@ -47,30 +280,192 @@ pub fn run_processing(
                     break;
                 }
             }
-            if let Ok((diff, new_config)) = rx_pipeconf.try_recv() {
+            // Apply all pending changes, since several may have arrived during one chunk
+            while let Ok((diff, new_config)) = rx_pipeconf.try_recv() {
                 trace!("Message received on config channel");
                 match diff {
                     config::ConfigChange::Pipeline | config::ConfigChange::MixerParameters => {
                         debug!("Rebuilding pipeline.");
-                        let new_pipeline =
-                            filters::Pipeline::from_config(new_config, processing_status.clone());
-                        pipeline = new_pipeline;
+                        let fade_length =
+                            (new_config.crossfade_time * samplerate as PrcFmt).round() as usize;
+                        let new_pipeline = filters::Pipeline::from_config(
+                            new_config.clone(),
+                            processing_status.clone(),
+                        );
+                        if fade_length > 0 {
+                            debug!("Crossfading to new pipeline over {} frames.", fade_length);
+                        }
+                        pipeline.replace(new_pipeline, new_config, fade_length);
                     }
-                    config::ConfigChange::FilterParameters { filters, mixers } => {
+                    config::ConfigChange::FilterParameters {
+                        filters,
+                        mixers,
+                        processors,
+                    } => {
                         debug!(
-                            "Updating parameters of filters: {:?}, mixers: {:?}.",
-                            filters, mixers
+                            "Updating parameters of filters: {:?}, mixers: {:?}, processors: {:?}.",
+                            filters, mixers, processors
                         );
-                        pipeline.update_parameters(new_config, filters, mixers);
+                        pipeline.update_parameters(&new_config, &filters, &mixers, &processors);
                     }
                     config::ConfigChange::Devices => {
                         let msg = AudioMessage::EndOfStream;
                         tx_pb.send(msg).unwrap();
-                        break;
+                        break 'processing;
                     }
                     _ => {}
                 };
-            };
+            }
         }
+        capture_status.write().unwrap().loudness = LoudnessLevels::default();
+        playback_status.write().unwrap().loudness = LoudnessLevels::default();
+        let mut stat = status.write().unwrap();
+        stat.reset_loudness = false;
+        stat.processing_load = ProcessingLoad::default();
+        stat.capture_spectrum = Spectrum::default();
+        stat.playback_spectrum = Spectrum::default();
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use audiodevice::AudioChunk;
+    use config::{Configuration, Filter};
+    use filters::Pipeline;
+    use processing::{crossfade, CrossfadingPipeline};
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use PrcFmt;
+    use ProcessingParameters;
+
+    fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    #[test]
+    fn crossfade_over_two_chunks() {
+        let mut values = Vec::new();
+        for position in [0, 4].iter() {
+            let mut new = AudioChunk::new(vec![vec![1.0; 4]], 1.0, 1.0, 4, 4);
+            let old = AudioChunk::new(vec![vec![-1.0; 4]], -1.0, -1.0, 4, 4);
+            crossfade(&mut new, &old, *position, 6);
+            values.extend(new.waveforms[0].clone());
+        }
+        // starts at the old value, passes zero halfway, and stays at the new value when done
+        assert!(is_close(values[0], -1.0, 1.0e-9));
+        assert!(is_close(values[3], 0.0, 1.0e-9));
+        assert!(values.windows(2).all(|pair| pair[1] >= pair[0]));
+        assert!(is_close(values[6], 1.0, 1.0e-9));
+        assert!(is_close(values[7], 1.0, 1.0e-9));
+    }
+
+    fn gain_config(gain: PrcFmt, inverted: bool) -> Configuration {
+        let yaml = format!(
+            r#"
+devices:
+  samplerate: 48000
+  chunksize: 4
+  capture:
+    type: Stdin
+    channels: 1
+    format: FLOAT64LE
+  playback:
+    type: Stdout
+    channels: 1
+    format: FLOAT64LE
+filters:
+  gain:
+    type: Gain
+    parameters:
+      gain: {}
+      inverted: {}
+pipeline:
+  - type: Filter
+    channel: 0
+    names: [gain]
+"#,
+            gain, inverted
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    fn gain_pipeline(gain: PrcFmt, inverted: bool) -> (Pipeline, Configuration) {
+        let conf = gain_config(gain, inverted);
+        let params = Arc::new(RwLock::new(ProcessingParameters {
+            volume: 0.0,
+            mute: false,
+            faders: HashMap::new(),
+        }));
+        (Pipeline::from_config(conf.clone(), params), conf)
+    }
+
+    fn process(pipeline: &mut CrossfadingPipeline, values: &mut Vec<PrcFmt>) {
+        let chunk = AudioChunk::new(vec![vec![1.0; 4]], 1.0, 1.0, 4, 4);
+        values.extend(pipeline.process_chunk(chunk).waveforms[0].clone());
+    }
+
+    #[test]
+    fn new_crossfade_continues_from_running_one() {
+        let (first, conf) = gain_pipeline(0.0, false);
+        let mut pipeline = CrossfadingPipeline::new(first, conf);
+        let mut values = Vec::new();
+        process(&mut pipeline, &mut values);
+        let (second, conf) = gain_pipeline(0.0, true);
+        pipeline.replace(second, conf, 8);
+        process(&mut pipeline, &mut values);
+        // start a new fade halfway through the first one
+        let (third, conf) = gain_pipeline(0.0, false);
+        pipeline.replace(third, conf, 8);
+        process(&mut pipeline, &mut values);
+        process(&mut pipeline, &mut values);
+        process(&mut pipeline, &mut values);
+        assert!(pipeline.fade.is_none());
+        // no jumps when the second fade starts
+        assert!(values
+            .windows(2)
+            .all(|pair| (pair[1] - pair[0]).abs() < 0.5));
+        assert!(is_close(values[4], 1.0, 1.0e-9));
+        assert!(is_close(*values.last().unwrap(), 1.0, 1.0e-9));
+    }
+
+    #[test]
+    fn update_parameters_during_fade() {
+        let (first, conf) = gain_pipeline(0.0, false);
+        let mut pipeline = CrossfadingPipeline::new(first, conf);
+        let (second, conf) = gain_pipeline(-6.0, false);
+        pipeline.replace(second, conf, 16);
+        let mut values = Vec::new();
+        process(&mut pipeline, &mut values);
+        // change the gain of both pipelines, -20 dB gives 0.1
+        let new_conf = gain_config(-20.0, false);
+        pipeline.update_parameters(&new_conf, &["gain".to_string()], &[], &[]);
+        let mut values = Vec::new();
+        process(&mut pipeline, &mut values);
+        assert!(pipeline.fade.is_some());
+        assert!(values.iter().all(|value| is_close(*value, 0.1, 1.0e-9)));
+        // a filter that has another type in the old pipeline is only updated in the new one
+        let mut delay_conf = gain_config(0.0, false);
+        delay_conf.filters.insert(
+            "gain".to_string(),
+            serde_yaml::from_str("type: Delay\nparameters:\n  delay: 0\n").unwrap(),
+        );
+        let params = Arc::new(RwLock::new(ProcessingParameters {
+            volume: 0.0,
+            mute: false,
+            faders: HashMap::new(),
+        }));
+        let delay = Pipeline::from_config(delay_conf.clone(), params);
+        let mut pipeline = CrossfadingPipeline::new(delay, delay_conf);
+        let (gain, conf) = gain_pipeline(0.0, false);
+        pipeline.replace(gain, conf, 16);
+        pipeline.update_parameters(&new_conf, &["gain".to_string()], &[], &[]);
+        let mut values = Vec::new();
+        process(&mut pipeline, &mut values);
+        // the faded out delay passes 1.0, and the new gain gives 0.1
+        assert!(is_close(values[0], 1.0, 1.0e-9));
+        assert!(values[3] < 1.0 && values[3] > 0.1);
+        let old = &pipeline.fade.as_ref().unwrap().old;
+        assert!(matches!(old.config.filters["gain"], Filter::Delay { .. }));
+    }
+}
@@ -0,0 +1,402 @@
+use audiodevice::AudioChunk;
+use biquad::BiquadCoefficients;
+use biquadcombo::BiquadCombo;
+use config;
+use config::SampleFormat;
+use diffeq::DiffEq;
+use filedevice::write_wav_header;
+use filters;
+use filters::Pipeline;
+use num_complex::Complex;
+use realfft::RealFftPlanner;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use NewValue;
+use PrcFmt;
+use ProcessingParameters;
+use Res;
+
+/// Default length in samples of an exported impulse response.
+pub const DEFAULT_RESPONSE_LENGTH: usize = 65536;
+/// Number of frequency points in an exported frequency response.
+const NBR_FREQUENCIES: usize = 500;
+/// Lowest frequency in an exported frequency response.
+const MIN_FREQUENCY: PrcFmt = 10.0;
+/// Smallest FFT used for calculating a frequency response from an impulse response.
+const MIN_FFT_SIZE: usize = 16384;
+
+/// Feed a unit impulse to one capture channel of a newly built pipeline,
+/// and record `length` samples of each output channel.
+/// The other capture channels are silent, so that each output gives the response
+/// of the path from the selected input to that output.
+/// The config must already be validated.
+pub fn impulse_response(
+    conf: &config::Configuration,
+    length: usize,
+    input_channel: usize,
+) -> Vec<Vec<PrcFmt>> {
+    let chunksize = conf.devices.chunksize;
+    let channels_in = conf.devices.capture.channels();
+    let channels_out = conf.devices.playback.channels();
+    let processing_params = Arc::new(RwLock::new(ProcessingParameters {
+        volume: 0.0,
+        mute: false,
+        faders: HashMap::new(),
+    }));
+    let mut pipeline = Pipeline::from_config(conf.clone(), processing_params);
+    let mut response = vec![Vec::with_capacity(length); channels_out];
+    let mut first = true;
+    while response[0].len() < length {
+        let mut waveforms = vec![vec![0.0; chunksize]; channels_in];
+        if first {
+            waveforms[input_channel][0] = 1.0;
+            first = false;
+        }
+        let chunk = AudioChunk::new(waveforms, 1.0, 0.0, chunksize, chunksize);
+        let chunk = pipeline.process_chunk(chunk);
+        for (resp, wf) in response.iter_mut().zip(chunk.waveforms.iter()) {
+            let nbr = (length - resp.len()).min(wf.len());
+            resp.extend_from_slice(&wf[0..nbr]);
+        }
+    }
+    response
+}
+
+/// Get logarithmically spaced frequencies from `fmin` to `fmax`.
+pub fn log_frequencies(nbr: usize, fmin: PrcFmt, fmax: PrcFmt) -> Vec<PrcFmt> {
+    if nbr < 2 {
+        return vec![fmin; nbr];
+    }
+    let step = (fmax / fmin).ln() / (nbr - 1) as PrcFmt;
+    (0..nbr)
+        .map(|n| fmin * (step * n as PrcFmt).exp())
+        .collect()
+}
+
+/// Evaluate the discrete time Fourier transform of an impulse response at the given frequencies.
+/// The transform is calculated with an FFT of the zero padded impulse,
+/// and interpolated linearly between the bins.
+pub fn frequency_response(
+    impulse: &[PrcFmt],
+    samplerate: usize,
+    frequencies: &[PrcFmt],
+) -> Vec<Complex<PrcFmt>> {
+    // pad to at least twice the length to get a smooth interpolation
+    let fft_size = (2 * impulse.len()).max(MIN_FFT_SIZE).next_power_of_two();
+    let mut planner = RealFftPlanner::<PrcFmt>::new();
+    let fft = planner.plan_fft_forward(fft_size);
+    let mut input = fft.make_input_vec();
+    let mut spectrum = fft.make_output_vec();
+    input[0..impulse.len()].copy_from_slice(impulse);
+    fft.process(&mut input, &mut spectrum).unwrap();
+    let last_bin = fft_size / 2;
+    frequencies
+        .iter()
+        .map(|f| {
+            // the response of a real impulse repeats every samplerate and is symmetric around zero
+            let relative = (f / samplerate as PrcFmt).rem_euclid(1.0);
+            let mirrored = relative > 0.5;
+            let position = if mirrored { 1.0 - relative } else { relative } * fft_size as PrcFmt;
+            let bin = (position.floor() as usize).min(last_bin);
+            let next = (bin + 1).min(last_bin);
+            let fraction = position - bin as PrcFmt;
+            let value = spectrum[bin] * (1.0 - fraction) + spectrum[next] * fraction;
+            if mirrored {
+                value.conj()
+            } else {
+                value
+            }
+        })
+        .collect()
+}
+
+/// Get the magnitude in dB of a response value.
+/// The magnitude is limited to a lowest value of -200 dB, to avoid minus infinity for a zero response.
+pub fn magnitude_db(value: &Complex<PrcFmt>) -> PrcFmt {
+    20.0 * value.norm().max(1.0e-10).log10()
+}
+
+/// Get the analytic frequency response of a single filter at the given frequencies.
+/// This is supported for the Conv, Biquad, BiquadCombo, DiffEq and Gain filter types.
+pub fn filter_frequency_response(
+    conf: &config::Filter,
+    samplerate: usize,
+    frequencies: &[PrcFmt],
+) -> Res<Vec<Complex<PrcFmt>>> {
+    match conf {
+        config::Filter::Conv { parameters, .. } => {
+            let coeffs = filters::read_conv_coefficients(parameters)?;
+            Ok(frequency_response(&coeffs, samplerate, frequencies))
+        }
+        config::Filter::Biquad { parameters } => {
+            let coeffs = BiquadCoefficients::from_config(samplerate, parameters.clone());
+            Ok(coeffs.frequency_response(samplerate, frequencies))
+        }
+        config::Filter::BiquadCombo { parameters } => {
+            let combo = BiquadCombo::from_config("".to_string(), samplerate, parameters.clone());
+            Ok(combo.frequency_response(frequencies))
+        }
+        config::Filter::DiffEq { parameters } => {
+            let diffeq = DiffEq::from_config("".to_string(), parameters.clone());
+            Ok(diffeq.frequency_response(samplerate, frequencies))
+        }
+        config::Filter::Gain { parameters } => {
+            let mut gain = if parameters.mute {
+                0.0
+            } else {
+                PrcFmt::new(10.0).powf(parameters.gain / 20.0)
+            };
+            if parameters.inverted {
+                gain = -gain;
+            }
+            Ok(vec![Complex::new(gain, 0.0); frequencies.len()])
+        }
+        _ => Err(config::ConfigError::new(
+            "Frequency response is only available for Conv, Biquad, BiquadCombo, DiffEq and Gain filters",
+        )
+        .into()),
+    }
+}
+
+/// Write waveforms to a 32-bit float wav file.
+pub fn write_wav(filename: &str, waveforms: &[Vec<PrcFmt>], samplerate: usize) -> Res<()> {
+    let channels = waveforms.len();
+    let frames = waveforms.iter().map(|wf| wf.len()).max().unwrap_or(0);
+    let data_length = (frames * channels * 4) as u32;
+    let mut file = BufWriter::new(File::create(filename)?);
+    write_wav_header(
+        &mut file,
+        channels,
+        samplerate,
+        &SampleFormat::FLOAT32LE,
+        data_length,
+    )?;
+    for frame in 0..frames {
+        for wf in waveforms.iter() {
+            let value = wf.get(frame).copied().unwrap_or(0.0) as f32;
+            file.write_all(&value.to_le_bytes())?;
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Write the magnitude in dB and phase in degrees of frequency responses to a csv file.
+pub fn write_csv(
+    filename: &str,
+    frequencies: &[PrcFmt],
+    responses: &[Vec<Complex<PrcFmt>>],
+) -> Res<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    let mut header = vec!["frequency".to_string()];
+    for n in 0..responses.len() {
+        header.push(format!("magnitude_{}", n));
+        header.push(format!("phase_{}", n));
+    }
+    writeln!(file, "{}", header.join(","))?;
+    for (idx, freq) in frequencies.iter().enumerate() {
+        let mut line = vec![format!("{}", freq)];
+        for resp in responses.iter() {
+            line.push(format!("{}", magnitude_db(&resp[idx])));
+            line.push(format!("{}", resp[idx].arg().to_degrees()));
+        }
+        writeln!(file, "{}", line.join(","))?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Export the responses of a pipeline, one pair of files for each capture channel.
+/// The impulse responses from capture channel N to all output channels are written to `<basename>_inN.wav`,
+/// and the corresponding frequency responses to `<basename>_inN.csv`.
+pub fn export_response(conf: &config::Configuration, basename: &str, length: usize) -> Res<()> {
+    let samplerate = conf.devices.samplerate;
+    let frequencies = log_frequencies(NBR_FREQUENCIES, MIN_FREQUENCY, samplerate as PrcFmt / 2.0);
+    for input_channel in 0..conf.devices.capture.channels() {
+        let impulse = impulse_response(conf, length, input_channel);
+        let wavname = format!("{}_in{}.wav", basename, input_channel);
+        write_wav(&wavname, &impulse, samplerate)?;
+        let responses = impulse
+            .iter()
+            .map(|ir| frequency_response(ir, samplerate, &frequencies))
+            .collect::<Vec<_>>();
+        let csvname = format!("{}_in{}.csv", basename, input_channel);
+        write_csv(&csvname, &frequencies, &responses)?;
+        info!(
+            "Exported impulse response from input {} to '{}' and frequency response to '{}'",
+            input_channel, wavname, csvname
+        );
+    }
+    Ok(())
+}
+
+/// Get the base name for an export requested via websocket, placed in the export directory.
+/// Only a plain file name is accepted, so that a client can't write files outside of the directory.
+pub fn export_basename(directory: &str, name: &str) -> Res<String> {
+    let is_plain = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains('/')
+        && !name.contains('\\');
+    if !is_plain {
+        return Err(config::ConfigError::new(
+            "The export name must be a plain file name, without any directory",
+        )
+        .into());
+    }
+    Ok(Path::new(directory)
+        .join(name)
+        .to_string_lossy()
+        .into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Configuration;
+    use config::{
+        BiquadComboParameters, BiquadParameters, ConvParameters, ConvPartitioning, Filter,
+    };
+    use num_complex::Complex;
+    use response::{
+        export_basename, filter_frequency_response, frequency_response, impulse_response,
+        log_frequencies, magnitude_db,
+    };
+    use PrcFmt;
+
+    fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    #[test]
+    fn check_log_frequencies() {
+        let freqs = log_frequencies(4, 10.0, 10000.0);
+        assert!(is_close(freqs[0], 10.0, 1.0e-6));
+        assert!(is_close(freqs[1], 100.0, 1.0e-6));
+        assert!(is_close(freqs[3], 10000.0, 1.0e-6));
+    }
+
+    #[test]
+    fn check_delay_response() {
+        // a delay of one sample gives unity gain and a phase of -90 degrees at fs/4
+        let impulse = vec![0.0, 1.0, 0.0, 0.0];
+        let resp = frequency_response(&impulse, 48000, &[12000.0]);
+        assert!(is_close(resp[0].norm(), 1.0, 1.0e-6));
+        assert!(is_close(resp[0].arg().to_degrees(), -90.0, 1.0e-6));
+    }
+
+    #[test]
+    fn check_interpolated_response() {
+        // the response of [0.5, 1.0, 0.5] is exp(-j*omega) * (1 + cos(omega))
+        let impulse = vec![0.5, 1.0, 0.5];
+        let freq = 1234.5;
+        let omega = 2.0 * std::f64::consts::PI as PrcFmt * freq / 48000.0;
+        let resp = frequency_response(&impulse, 48000, &[freq, 48000.0 - freq]);
+        assert!(is_close(resp[0].norm(), 1.0 + omega.cos(), 1.0e-6));
+        assert!(is_close(resp[0].arg(), -omega, 1.0e-4));
+        // above the Nyquist frequency the response is mirrored
+        assert!(is_close(resp[1].norm(), resp[0].norm(), 1.0e-9));
+        assert!(is_close(resp[1].arg(), -resp[0].arg(), 1.0e-9));
+    }
+
+    #[test]
+    fn zero_magnitude_is_finite() {
+        let db = magnitude_db(&Complex::new(0.0, 0.0));
+        assert!(db.is_finite());
+        assert!(is_close(db, -200.0, 1.0e-6));
+    }
+
+    #[test]
+    fn check_filter_response() {
+        let freqs = [10.0, 1000.0, 20000.0];
+        let lowpass = Filter::Biquad {
+            parameters: BiquadParameters::Lowpass {
+                freq: 1000.0,
+                q: 0.5_f64.sqrt() as PrcFmt,
+            },
+        };
+        let resp = filter_frequency_response(&lowpass, 48000, &freqs).unwrap();
+        assert!(is_close(20.0 * resp[0].norm().log10(), 0.0, 0.01));
+        assert!(is_close(20.0 * resp[1].norm().log10(), -3.01, 0.01));
+        assert!(is_close(resp[1].arg().to_degrees(), -90.0, 0.01));
+        // a 4th order Linkwitz-Riley is -6 dB at the crossover frequency
+        let lr4 = Filter::BiquadCombo {
+            parameters: BiquadComboParameters::LinkwitzRileyLowpass {
+                freq: 1000.0,
+                order: 4,
+            },
+        };
+        let resp = filter_frequency_response(&lr4, 48000, &freqs).unwrap();
+        assert!(is_close(20.0 * resp[1].norm().log10(), -6.02, 0.01));
+        // a convolution with a unit impulse is flat
+        let conv = Filter::Conv {
+            parameters: ConvParameters::Values {
+                values: vec![1.0, 0.0, 0.0],
+                length: 0,
+            },
+            partitioning: ConvPartitioning::Uniform,
+        };
+        let resp = filter_frequency_response(&conv, 48000, &freqs).unwrap();
+        for value in resp.iter() {
+            assert!(is_close(value.norm(), 1.0, 1.0e-9));
+        }
+    }
+
+    #[test]
+    fn response_per_input() {
+        let yaml = r#"
+devices:
+  samplerate: 48000
+  chunksize: 4
+  capture:
+    type: Stdin
+    channels: 2
+    format: FLOAT64LE
+  playback:
+    type: Stdout
+    channels: 1
+    format: FLOAT64LE
+mixers:
+  sum:
+    channels:
+      in: 2
+      out: 1
+    mapping:
+      - dest: 0
+        sources:
+          - channel: 0
+            gain: 0
+            inverted: false
+          - channel: 1
+            gain: -6.0206
+            inverted: false
+pipeline:
+  - type: Mixer
+    name: sum
+"#;
+        let conf: Configuration = serde_yaml::from_str(yaml).unwrap();
+        // each input gives its own path to the output, instead of the sum of both
+        let from_left = impulse_response(&conf, 8, 0);
+        let from_right = impulse_response(&conf, 8, 1);
+        assert_eq!(from_left.len(), 1);
+        assert!(is_close(from_left[0][0], 1.0, 1.0e-6));
+        assert!(is_close(from_right[0][0], 0.5, 1.0e-4));
+        assert!(from_left[0][1..].iter().all(|value| *value == 0.0));
+    }
+
+    #[test]
+    fn check_export_basename() {
+        assert_eq!(
+            export_basename("/tmp/exports", "response").unwrap(),
+            "/tmp/exports/response"
+        );
+        assert!(export_basename("/tmp/exports", "../response").is_err());
+        assert!(export_basename("/tmp/exports", "/home/user/response").is_err());
+        assert!(export_basename("/tmp/exports", "..").is_err());
+        assert!(export_basename("/tmp/exports", "").is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use config;
+use rand::rngs::ThreadRng;
+use rand::{thread_rng, Rng};
+use rand_distr::StandardNormal;
+use rawsample;
+use rawsample::SampleWriter;
+use std::io::{Cursor, Read};
+
+use NewValue;
+use PrcFmt;
+use Res;
+
+/// RMS value of the pink noise filter output, for white noise input with unit RMS.
+const PINK_NOISE_RMS: PrcFmt = 3.0525;
+
+/// State of the generated signal.
+enum SignalState {
+    Sine {
+        phase_step: PrcFmt,
+        phase: PrcFmt,
+    },
+    Sweep {
+        start_freq: PrcFmt,
+        ratio: PrcFmt,
+        length: usize,
+        position: usize,
+        phase: PrcFmt,
+    },
+    WhiteNoise,
+    PinkNoise {
+        states: [PrcFmt; 7],
+    },
+    Silence,
+}
+
+/// A signal generator, providing the generated signal as raw FLOAT64LE samples
+/// via the Read trait, so that it can be used as the source of a capture device.
+pub struct SignalGenerator {
+    active_channels: Vec<bool>,
+    samplerate: usize,
+    amplitude: PrcFmt,
+    state: SignalState,
+    rng: ThreadRng,
+    values: Vec<PrcFmt>,
+    frame: Vec<u8>,
+    frame_pos: usize,
+}
+
+fn level_to_amplitude(level: PrcFmt) -> PrcFmt {
+    PrcFmt::new(10.0).powf(level / 20.0)
+}
+
+impl SignalGenerator {
+    pub fn new(
+        signal: config::Signal,
+        channels: usize,
+        active_channels: Option<Vec<usize>>,
+        samplerate: usize,
+    ) -> Self {
+        let pi = std::f64::consts::PI as PrcFmt;
+        let (state, amplitude) = match signal {
+            config::Signal::Sine { freq, level } => (
+                SignalState::Sine {
+                    phase_step: 2.0 * pi * freq / samplerate as PrcFmt,
+                    phase: 0.0,
+                },
+                level_to_amplitude(level),
+            ),
+            config::Signal::Sweep {
+                start_freq,
+                end_freq,
+                length,
+                level,
+            } => (
+                SignalState::Sweep {
+                    start_freq,
+                    ratio: end_freq / start_freq,
+                    length: (length * samplerate as PrcFmt).round() as usize,
+                    position: 0,
+                    phase: 0.0,
+                },
+                level_to_amplitude(level),
+            ),
+            config::Signal::WhiteNoise { level } => {
+                (SignalState::WhiteNoise, level_to_amplitude(level))
+            }
+            config::Signal::PinkNoise { level } => (
+                SignalState::PinkNoise { states: [0.0; 7] },
+                level_to_amplitude(level) / PINK_NOISE_RMS,
+            ),
+            config::Signal::Silence => (SignalState::Silence, 0.0),
+        };
+        let active_channels = match active_channels {
+            Some(active) => (0..channels).map(|ch| active.contains(&ch)).collect(),
+            None => vec![true; channels],
+        };
+        SignalGenerator {
+            active_channels,
+            samplerate,
+            amplitude,
+            state,
+            rng: thread_rng(),
+            values: vec![0.0; channels],
+            frame: vec![0u8; 8 * channels],
+            // start with an empty frame, so that the first read generates a new one
+            frame_pos: 8 * channels,
+        }
+    }
+
+    /// Calculate the next value of the signal.
+    fn next_value(&mut self) -> PrcFmt {
+        let pi = std::f64::consts::PI as PrcFmt;
+        match &mut self.state {
+            SignalState::Sine { phase_step, phase } => {
+                let value = phase.sin();
+                *phase = (*phase + *phase_step) % (2.0 * pi);
+                value
+            }
+            SignalState::Sweep {
+                start_freq,
+                ratio,
+                length,
+                position,
+                phase,
+            } => {
+                // logarithmic sweep, restarting from the start frequency when done
+                let value = phase.sin();
+                let freq = *start_freq * ratio.powf(*position as PrcFmt / *length as PrcFmt);
+                *phase = (*phase + 2.0 * pi * freq / self.samplerate as PrcFmt) % (2.0 * pi);
+                *position += 1;
+                if *position >= *length {
+                    *position = 0;
+                    *phase = 0.0;
+                }
+                value
+            }
+            SignalState::WhiteNoise => self.rng.sample(StandardNormal),
+            SignalState::PinkNoise { states } => {
+                // Paul Kellet's refined pink noise filter
+                let white: PrcFmt = self.rng.sample(StandardNormal);
+                states[0] = 0.99886 * states[0] + white * 0.0555179;
+                states[1] = 0.99332 * states[1] + white * 0.0750759;
+                states[2] = 0.96900 * states[2] + white * 0.1538520;
+                states[3] = 0.86650 * states[3] + white * 0.3104856;
+                states[4] = 0.55000 * states[4] + white * 0.5329522;
+                states[5] = -0.7616 * states[5] - white * 0.0168980;
+                let pink = states.iter().sum::<PrcFmt>() + white * 0.5362;
+                states[6] = white * 0.115926;
+                pink
+            }
+            SignalState::Silence => 0.0,
+        }
+    }
+
+    /// Generate the next frame, and store it as raw bytes.
+    /// This reuses the preallocated buffers, to avoid allocating in the capture thread.
+    fn generate_frame(&mut self) {
+        let value = self.amplitude * self.next_value();
+        for (sample, active) in self.values.iter_mut().zip(self.active_channels.iter()) {
+            *sample = if *active { value } else { 0.0 };
+        }
+        let mut cursor = Cursor::new(&mut self.frame[..]);
+        PrcFmt::write_samples(&self.values, &mut cursor, &rawsample::SampleFormat::F64LE).unwrap();
+        self.frame_pos = 0;
+    }
+}
+
+impl Read for SignalGenerator {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            if self.frame_pos >= self.frame.len() {
+                self.generate_frame();
+            }
+            let nbr = (self.frame.len() - self.frame_pos).min(buf.len() - written);
+            buf[written..written + nbr]
+                .copy_from_slice(&self.frame[self.frame_pos..self.frame_pos + nbr]);
+            self.frame_pos += nbr;
+            written += nbr;
+        }
+        Ok(written)
+    }
+}
+
+/// Validate the signal generator config, to give a helpful message intead of a panic.
+pub fn validate_config(
+    signal: &config::Signal,
+    channels: usize,
+    active_channels: &Option<Vec<usize>>,
+    samplerate: usize,
+) -> Res<()> {
+    let nyquist = samplerate as PrcFmt / 2.0;
+    let level = match signal {
+        config::Signal::Sine { freq, level } => {
+            if *freq <= 0.0 || *freq >= nyquist {
+                let msg = format!("Sine frequency must be in the range 0 to {} Hz", nyquist);
+                return Err(config::ConfigError::new(&msg).into());
+            }
+            *level
+        }
+        config::Signal::Sweep {
+            start_freq,
+            end_freq,
+            length,
+            level,
+        } => {
+            if *start_freq <= 0.0
+                || *end_freq <= 0.0
+                || *start_freq >= nyquist
+                || *end_freq >= nyquist
+            {
+                let msg = format!(
+                    "Sweep start and end frequencies must be in the range 0 to {} Hz",
+                    nyquist
+                );
+                return Err(config::ConfigError::new(&msg).into());
+            }
+            if *length * (samplerate as PrcFmt) < 1.0 {
+                return Err(config::ConfigError::new("Sweep length must be positive").into());
+            }
+            *level
+        }
+        config::Signal::WhiteNoise { level } | config::Signal::PinkNoise { level } => *level,
+        config::Signal::Silence => 0.0,
+    };
+    if level > 0.0 {
+        return Err(config::ConfigError::new("Signal level must be 0 dB or lower").into());
+    }
+    if let Some(active) = active_channels {
+        for ch in active.iter() {
+            if *ch >= channels {
+                let msg = format!(
+                    "Invalid active channel {} for signal generator, max is {}.",
+                    ch,
+                    channels - 1
+                );
+                return Err(config::ConfigError::new(&msg).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Signal;
+    use rawsample::{SampleFormat, SampleReader};
+    use signalgenerator::{validate_config, SignalGenerator};
+    use std::io::{Cursor, Read};
+    use PrcFmt;
+
+    fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    fn generate(generator: &mut SignalGenerator, frames: usize) -> Vec<PrcFmt> {
+        let channels = generator.values.len();
+        let mut buf = vec![0u8; 8 * frames * channels];
+        generator.read_exact(&mut buf).unwrap();
+        let mut values = vec![0.0; frames * channels];
+        PrcFmt::read_samples(&mut Cursor::new(&buf), &mut values, &SampleFormat::F64LE).unwrap();
+        values
+    }
+
+    fn rms(values: &[PrcFmt]) -> PrcFmt {
+        (values.iter().map(|v| v * v).sum::<PrcFmt>() / values.len() as PrcFmt).sqrt()
+    }
+
+    #[test]
+    fn sine_on_active_channel() {
+        let signal = Signal::Sine {
+            freq: 1000.0,
+            level: -6.0,
+        };
+        let mut generator = SignalGenerator::new(signal, 2, Some(vec![1]), 48000);
+        let values = generate(&mut generator, 48);
+        // 48 samples is one period
+        assert!(is_close(values[0], 0.0, 1.0e-9));
+        assert!(is_close(values[25], 0.501187, 1.0e-6));
+        assert!(values.iter().step_by(2).all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn noise_levels() {
+        let signal = Signal::WhiteNoise { level: -20.0 };
+        let mut generator = SignalGenerator::new(signal, 1, None, 48000);
+        let values = generate(&mut generator, 100000);
+        assert!(is_close(rms(&values), 0.1, 0.005));
+        let signal = Signal::PinkNoise { level: -20.0 };
+        let mut generator = SignalGenerator::new(signal, 1, None, 48000);
+        let values = generate(&mut generator, 100000);
+        assert!(is_close(rms(&values), 0.1, 0.01));
+    }
+
+    #[test]
+    fn check_config() {
+        let signal = Signal::Sweep {
+            start_freq: 20.0,
+            end_freq: 20000.0,
+            length: 5.0,
+            level: -10.0,
+        };
+        assert!(validate_config(&signal, 2, &None, 48000).is_ok());
+        assert!(validate_config(&signal, 2, &Some(vec![2]), 48000).is_err());
+        assert!(validate_config(&signal, 2, &None, 32000).is_err());
+        let signal = Signal::Sine {
+            freq: 1000.0,
+            level: 3.0,
+        };
+        assert!(validate_config(&signal, 2, &None, 48000).is_err());
+    }
+}
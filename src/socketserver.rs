@@ -2,24 +2,35 @@ use clap::crate_version;
 #[cfg(feature = "secure-websocket")]
 use native_tls::{Identity, TlsAcceptor, TlsStream};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 #[cfg(feature = "secure-websocket")]
 use std::fs::File;
+use std::io;
 #[cfg(feature = "secure-websocket")]
 use std::io::Read;
+use std::mem;
 use std::net::{TcpListener, TcpStream};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 use tungstenite::server::accept;
 use tungstenite::Message;
 use tungstenite::WebSocket;
 
 use crate::{
-    list_supported_devices, CaptureStatus, PlaybackStatus, ProcessingParameters, ProcessingStatus,
-    StopReason,
+    list_supported_devices, CaptureStatus, Fader, PipelineUpdates, PlaybackStatus, ProcessingLoad,
+    ProcessingParameters, ProcessingStatus, StopReason, MAIN_FADER,
 };
+use basicfilters;
 use config;
+use filters;
+use latency;
+use loudnessmeter::LoudnessLevels;
+use response;
+use spectrum::Spectrum;
 use ExitRequest;
+use PrcFmt;
 use ProcessingState;
 use Res;
 
@@ -31,10 +42,22 @@ pub struct SharedData {
     pub active_config_path: Arc<Mutex<Option<String>>>,
     pub new_config: Arc<Mutex<Option<config::Configuration>>>,
     pub previous_config: Arc<Mutex<Option<config::Configuration>>>,
+    pub pipeline_updates: PipelineUpdates,
     pub capture_status: Arc<RwLock<CaptureStatus>>,
     pub playback_status: Arc<RwLock<PlaybackStatus>>,
     pub processing_status: Arc<RwLock<ProcessingParameters>>,
     pub status: Arc<RwLock<ProcessingStatus>>,
+    pub export_dir: Option<String>,
+}
+
+/// How long to wait for an incoming message, before checking if there are any events to push.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Shortest allowed interval for pushing events to a subscriber.
+const MIN_SUBSCRIBE_INTERVAL: usize = 50;
+
+fn default_subscribe_interval() -> usize {
+    1000
 }
 
 #[derive(Debug, Clone)]
@@ -52,6 +75,9 @@ enum WsCommand {
     SetConfigName(String),
     SetConfig(String),
     SetConfigJson(String),
+    PatchConfig(serde_json::Value),
+    GetFilter(String),
+    SetFilter(String, config::Filter),
     Reload,
     GetConfig,
     GetPreviousConfig,
@@ -72,6 +98,12 @@ enum WsCommand {
     SetVolume(f32),
     GetMute,
     SetMute(bool),
+    AdjustVolume(f32),
+    GetFaderVolume(String),
+    SetFaderVolume(String, f32),
+    AdjustFaderVolume(String, f32),
+    GetFaderMute(String),
+    SetFaderMute(String, bool),
     GetVersion,
     GetState,
     GetStopReason,
@@ -79,11 +111,148 @@ enum WsCommand {
     GetClippedSamples,
     GetBufferLevel,
     GetSupportedDeviceTypes,
+    ExportResponse(String),
+    GetFilterFrequencyResponse(String, Vec<f32>),
+    GetLatency,
+    GetProcessingLoad,
+    GetCaptureSpectrum,
+    GetPlaybackSpectrum,
+    GetCaptureLoudness,
+    GetPlaybackLoudness,
+    ResetLoudness,
     Exit,
     Stop,
+    Subscribe {
+        events: Vec<WsEventType>,
+        #[serde(default = "default_subscribe_interval")]
+        interval: usize,
+    },
+    Unsubscribe,
     None,
 }
 
+/// The kinds of events that a client can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+enum WsEventType {
+    SignalLevels,
+    State,
+    Volume,
+    Config,
+    StopReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct SignalLevels {
+    capture_rms: Vec<f32>,
+    capture_peak: Vec<f32>,
+    playback_rms: Vec<f32>,
+    playback_peak: Vec<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct VolumeState {
+    volume: f32,
+    mute: bool,
+    faders: HashMap<String, Fader>,
+}
+
+/// Events that are pushed to subscribers.
+#[derive(Debug, PartialEq, Serialize)]
+enum WsEvent {
+    SignalLevels(SignalLevels),
+    State(ProcessingState),
+    Volume(VolumeState),
+    Config(Option<String>),
+    StopReason(StopReason),
+}
+
+/// The subscription of a connection. Signal levels are pushed at every interval,
+/// while the other events are only pushed when the value has changed.
+struct Subscription {
+    events: Vec<WsEventType>,
+    interval: Duration,
+    last_push: Option<Instant>,
+    state: Option<ProcessingState>,
+    volume: Option<VolumeState>,
+    config: Option<(Option<String>, Option<config::Configuration>)>,
+    stop_reason: Option<StopReason>,
+}
+
+impl Subscription {
+    fn new(events: Vec<WsEventType>, interval: Duration) -> Self {
+        Subscription {
+            events,
+            interval,
+            last_push: None,
+            state: None,
+            volume: None,
+            config: None,
+            stop_reason: None,
+        }
+    }
+
+    /// Get the events that should be pushed now, if the interval has passed since the last time.
+    fn poll(&mut self, shared_data: &SharedData) -> Vec<WsEvent> {
+        if let Some(last) = self.last_push {
+            if last.elapsed() < self.interval {
+                return Vec::new();
+            }
+        }
+        self.last_push = Some(Instant::now());
+        let mut events = Vec::new();
+        for event_type in self.events.clone().iter() {
+            match event_type {
+                WsEventType::SignalLevels => {
+                    let capstat = shared_data.capture_status.read().unwrap();
+                    let pbstat = shared_data.playback_status.read().unwrap();
+                    events.push(WsEvent::SignalLevels(SignalLevels {
+                        capture_rms: capstat.signal_rms.clone(),
+                        capture_peak: capstat.signal_peak.clone(),
+                        playback_rms: pbstat.signal_rms.clone(),
+                        playback_peak: pbstat.signal_peak.clone(),
+                    }));
+                }
+                WsEventType::State => {
+                    let state = shared_data.capture_status.read().unwrap().state;
+                    if self.state != Some(state) {
+                        self.state = Some(state);
+                        events.push(WsEvent::State(state));
+                    }
+                }
+                WsEventType::Volume => {
+                    let procstat = shared_data.processing_status.read().unwrap();
+                    let volume = VolumeState {
+                        volume: procstat.volume,
+                        mute: procstat.mute,
+                        faders: procstat.faders.clone(),
+                    };
+                    if self.volume.as_ref() != Some(&volume) {
+                        self.volume = Some(volume.clone());
+                        events.push(WsEvent::Volume(volume));
+                    }
+                }
+                WsEventType::Config => {
+                    let path = shared_data.active_config_path.lock().unwrap().clone();
+                    let conf = shared_data.active_config.lock().unwrap().clone();
+                    let current = (path, conf);
+                    if self.config.as_ref() != Some(&current) {
+                        events.push(WsEvent::Config(current.0.clone()));
+                        self.config = Some(current);
+                    }
+                }
+                WsEventType::StopReason => {
+                    let stop_reason = shared_data.status.read().unwrap().stop_reason.clone();
+                    if self.stop_reason.as_ref() != Some(&stop_reason) {
+                        self.stop_reason = Some(stop_reason.clone());
+                        events.push(WsEvent::StopReason(stop_reason));
+                    }
+                }
+            }
+        }
+        events
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize)]
 enum WsResult {
     Ok,
@@ -92,6 +261,13 @@ enum WsResult {
 
 #[derive(Debug, PartialEq, Serialize)]
 enum WsReply {
+    Subscribe {
+        result: WsResult,
+    },
+    Unsubscribe {
+        result: WsResult,
+    },
+    Event(WsEvent),
     SetConfigName {
         result: WsResult,
     },
@@ -101,6 +277,18 @@ enum WsReply {
     SetConfigJson {
         result: WsResult,
     },
+    PatchConfig {
+        result: WsResult,
+        value: String,
+    },
+    GetFilter {
+        result: WsResult,
+        value: Option<config::Filter>,
+    },
+    SetFilter {
+        result: WsResult,
+        value: String,
+    },
     Reload {
         result: WsResult,
     },
@@ -177,6 +365,28 @@ enum WsReply {
         result: WsResult,
         value: bool,
     },
+    AdjustVolume {
+        result: WsResult,
+        value: f32,
+    },
+    GetFaderVolume {
+        result: WsResult,
+        value: f32,
+    },
+    SetFaderVolume {
+        result: WsResult,
+    },
+    AdjustFaderVolume {
+        result: WsResult,
+        value: f32,
+    },
+    GetFaderMute {
+        result: WsResult,
+        value: bool,
+    },
+    SetFaderMute {
+        result: WsResult,
+    },
     GetVersion {
         result: WsResult,
         value: String,
@@ -205,6 +415,41 @@ enum WsReply {
         result: WsResult,
         value: (Vec<String>, Vec<String>),
     },
+    ExportResponse {
+        result: WsResult,
+        value: String,
+    },
+    GetFilterFrequencyResponse {
+        result: WsResult,
+        value: (Vec<f32>, Vec<f32>),
+    },
+    GetLatency {
+        result: WsResult,
+        value: latency::Latency,
+    },
+    GetProcessingLoad {
+        result: WsResult,
+        value: ProcessingLoad,
+    },
+    GetCaptureSpectrum {
+        result: WsResult,
+        value: Spectrum,
+    },
+    GetPlaybackSpectrum {
+        result: WsResult,
+        value: Spectrum,
+    },
+    GetCaptureLoudness {
+        result: WsResult,
+        value: LoudnessLevels,
+    },
+    GetPlaybackLoudness {
+        result: WsResult,
+        value: LoudnessLevels,
+    },
+    ResetLoudness {
+        result: WsResult,
+    },
     Exit {
         result: WsResult,
     },
@@ -296,41 +541,60 @@ macro_rules! make_handler {
     ($t:ty, $n:ident) => {
         fn $n(websocket_res: Res<WebSocket<$t>>, shared_data_inst: &SharedData) {
             match websocket_res {
-                Ok(mut websocket) => loop {
-                    let msg_res = websocket.read_message();
-                    match msg_res {
-                        Ok(msg) => {
-                            trace!("received: {:?}", msg);
-                            let command = parse_command(msg);
-                            debug!("parsed command: {:?}", command);
-                            let reply = match command {
-                                Ok(cmd) => handle_command(cmd, &shared_data_inst),
-                                Err(err) => Some(WsReply::Invalid {
-                                    error: err.to_string(),
-                                }),
-                            };
-                            if let Some(rep) = reply {
+                Ok(mut websocket) => {
+                    let mut subscription: Option<Subscription> = None;
+                    'connection: loop {
+                        let msg_res = websocket.read_message();
+                        match msg_res {
+                            Ok(msg) => {
+                                trace!("received: {:?}", msg);
+                                let command = parse_command(msg);
+                                debug!("parsed command: {:?}", command);
+                                let reply = match command {
+                                    Ok(cmd) => {
+                                        handle_command(cmd, &shared_data_inst, &mut subscription)
+                                    }
+                                    Err(err) => Some(WsReply::Invalid {
+                                        error: err.to_string(),
+                                    }),
+                                };
+                                if let Some(rep) = reply {
+                                    let write_result = websocket.write_message(Message::text(
+                                        serde_json::to_string(&rep).unwrap(),
+                                    ));
+                                    if let Err(err) = write_result {
+                                        warn!("Failed to write: {}", err);
+                                        break;
+                                    }
+                                } else {
+                                    debug!("Sending no reply");
+                                }
+                            }
+                            Err(tungstenite::error::Error::Io(ref err))
+                                if err.kind() == io::ErrorKind::WouldBlock
+                                    || err.kind() == io::ErrorKind::TimedOut => {}
+                            Err(tungstenite::error::Error::ConnectionClosed) => {
+                                debug!("Connection was closed");
+                                break;
+                            }
+                            Err(err) => {
+                                warn!("Lost connection: {}", err);
+                                break;
+                            }
+                        }
+                        if let Some(sub) = subscription.as_mut() {
+                            for event in sub.poll(&shared_data_inst) {
                                 let write_result = websocket.write_message(Message::text(
-                                    serde_json::to_string(&rep).unwrap(),
+                                    serde_json::to_string(&WsReply::Event(event)).unwrap(),
                                 ));
                                 if let Err(err) = write_result {
-                                    warn!("Failed to write: {}", err);
-                                    break;
+                                    warn!("Failed to push event: {}", err);
+                                    break 'connection;
                                 }
-                            } else {
-                                debug!("Sending no reply");
                             }
                         }
-                        Err(tungstenite::error::Error::ConnectionClosed) => {
-                            debug!("Connection was closed");
-                            break;
-                        }
-                        Err(err) => {
-                            warn!("Lost connection: {}", err);
-                            break;
-                        }
                     }
-                },
+                }
                 Err(err) => warn!("Connection failed: {}", err),
             };
         }
@@ -347,6 +611,10 @@ fn accept_secure_stream(
     stream: Result<TcpStream, std::io::Error>,
 ) -> Res<tungstenite::WebSocket<TlsStream<TcpStream>>> {
     let ws = accept(acceptor.accept(stream?)?)?;
+    // Use a read timeout, to be able to push events while waiting for incoming messages
+    ws.get_ref()
+        .get_ref()
+        .set_read_timeout(Some(POLL_INTERVAL))?;
     Ok(ws)
 }
 
@@ -354,11 +622,42 @@ fn accept_plain_stream(
     stream: Result<TcpStream, std::io::Error>,
 ) -> Res<tungstenite::WebSocket<TcpStream>> {
     let ws = accept(stream?)?;
+    // Use a read timeout, to be able to push events while waiting for incoming messages
+    ws.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
     Ok(ws)
 }
 
-fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<WsReply> {
+fn handle_command(
+    command: WsCommand,
+    shared_data_inst: &SharedData,
+    subscription: &mut Option<Subscription>,
+) -> Option<WsReply> {
     match command {
+        WsCommand::Subscribe { events, interval } => {
+            if interval < MIN_SUBSCRIBE_INTERVAL {
+                warn!(
+                    "Subscribe interval must be at least {} ms",
+                    MIN_SUBSCRIBE_INTERVAL
+                );
+                return Some(WsReply::Subscribe {
+                    result: WsResult::Error,
+                });
+            }
+            debug!("Subscribing to {:?} every {} ms", events, interval);
+            *subscription = Some(Subscription::new(
+                events,
+                Duration::from_millis(interval as u64),
+            ));
+            Some(WsReply::Subscribe {
+                result: WsResult::Ok,
+            })
+        }
+        WsCommand::Unsubscribe => {
+            *subscription = None;
+            Some(WsReply::Unsubscribe {
+                result: WsResult::Ok,
+            })
+        }
         WsCommand::Reload => {
             shared_data_inst
                 .signal_reload
@@ -479,6 +778,8 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
             })
         }
         WsCommand::SetVolume(nbr) => {
+            let (min, max) = fader_limits(shared_data_inst, MAIN_FADER);
+            let nbr = nbr.max(min).min(max);
             let mut procstat = shared_data_inst.processing_status.write().unwrap();
             procstat.volume = nbr;
             Some(WsReply::SetVolume {
@@ -499,6 +800,80 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 result: WsResult::Ok,
             })
         }
+        WsCommand::AdjustVolume(step) => {
+            let (min, max) = fader_limits(shared_data_inst, MAIN_FADER);
+            let mut procstat = shared_data_inst.processing_status.write().unwrap();
+            let volume = (procstat.volume + step).max(min).min(max);
+            procstat.volume = volume;
+            Some(WsReply::AdjustVolume {
+                result: WsResult::Ok,
+                value: volume,
+            })
+        }
+        WsCommand::GetFaderVolume(name) => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            match procstat.get_fader(&name) {
+                Some(fader) => Some(WsReply::GetFaderVolume {
+                    result: WsResult::Ok,
+                    value: fader.volume,
+                }),
+                None => Some(WsReply::GetFaderVolume {
+                    result: WsResult::Error,
+                    value: 0.0,
+                }),
+            }
+        }
+        WsCommand::SetFaderVolume(name, nbr) => {
+            let (min, max) = fader_limits(shared_data_inst, &name);
+            let nbr = nbr.max(min).min(max);
+            let mut procstat = shared_data_inst.processing_status.write().unwrap();
+            let result = if procstat.set_fader_volume(&name, nbr) {
+                WsResult::Ok
+            } else {
+                WsResult::Error
+            };
+            Some(WsReply::SetFaderVolume { result })
+        }
+        WsCommand::AdjustFaderVolume(name, step) => {
+            let (min, max) = fader_limits(shared_data_inst, &name);
+            let mut procstat = shared_data_inst.processing_status.write().unwrap();
+            match procstat.get_fader(&name) {
+                Some(fader) => {
+                    let volume = (fader.volume + step).max(min).min(max);
+                    procstat.set_fader_volume(&name, volume);
+                    Some(WsReply::AdjustFaderVolume {
+                        result: WsResult::Ok,
+                        value: volume,
+                    })
+                }
+                None => Some(WsReply::AdjustFaderVolume {
+                    result: WsResult::Error,
+                    value: 0.0,
+                }),
+            }
+        }
+        WsCommand::GetFaderMute(name) => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            match procstat.get_fader(&name) {
+                Some(fader) => Some(WsReply::GetFaderMute {
+                    result: WsResult::Ok,
+                    value: fader.mute,
+                }),
+                None => Some(WsReply::GetFaderMute {
+                    result: WsResult::Error,
+                    value: false,
+                }),
+            }
+        }
+        WsCommand::SetFaderMute(name, mute) => {
+            let mut procstat = shared_data_inst.processing_status.write().unwrap();
+            let result = if procstat.set_fader_mute(&name, mute) {
+                WsResult::Ok
+            } else {
+                WsResult::Error
+            };
+            Some(WsReply::SetFaderMute { result })
+        }
         WsCommand::GetConfig => Some(WsReply::GetConfig {
             result: WsResult::Ok,
             value: serde_yaml::to_string(&*shared_data_inst.active_config.lock().unwrap()).unwrap(),
@@ -590,6 +965,49 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 }
             }
         }
+        WsCommand::PatchConfig(patch) => match patch_pending_config(shared_data_inst, &patch) {
+            Ok(()) => Some(WsReply::PatchConfig {
+                result: WsResult::Ok,
+                value: String::new(),
+            }),
+            Err(error) => {
+                error!("Error patching config: {}", error);
+                Some(WsReply::PatchConfig {
+                    result: WsResult::Error,
+                    value: error.to_string(),
+                })
+            }
+        },
+        WsCommand::GetFilter(name) => {
+            let filter = shared_data_inst
+                .active_config
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|conf| conf.filters.get(&name).cloned());
+            let result = if filter.is_some() {
+                WsResult::Ok
+            } else {
+                WsResult::Error
+            };
+            Some(WsReply::GetFilter {
+                result,
+                value: filter,
+            })
+        }
+        WsCommand::SetFilter(name, filter) => match set_filter(shared_data_inst, &name, filter) {
+            Ok(()) => Some(WsReply::SetFilter {
+                result: WsResult::Ok,
+                value: String::new(),
+            }),
+            Err(error) => {
+                error!("Error setting filter '{}': {}", name, error);
+                Some(WsReply::SetFilter {
+                    result: WsResult::Error,
+                    value: error.to_string(),
+                })
+            }
+        },
         WsCommand::ReadConfig(config_yml) => {
             match serde_yaml::from_str::<config::Configuration>(&config_yml) {
                 Ok(conf) => Some(WsReply::ReadConfig {
@@ -666,14 +1084,367 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 value: devs,
             })
         }
+        WsCommand::ExportResponse(name) => match export_response(shared_data_inst, &name) {
+            Ok(basename) => Some(WsReply::ExportResponse {
+                result: WsResult::Ok,
+                value: basename,
+            }),
+            Err(error) => {
+                error!("Failed to export response: {}", error);
+                Some(WsReply::ExportResponse {
+                    result: WsResult::Error,
+                    value: error.to_string(),
+                })
+            }
+        },
+        WsCommand::GetFilterFrequencyResponse(name, frequencies) => {
+            match filter_frequency_response(shared_data_inst, &name, &frequencies) {
+                Ok(value) => Some(WsReply::GetFilterFrequencyResponse {
+                    result: WsResult::Ok,
+                    value,
+                }),
+                Err(error) => {
+                    error!("Failed to get frequency response: {}", error);
+                    Some(WsReply::GetFilterFrequencyResponse {
+                        result: WsResult::Error,
+                        value: (Vec::new(), Vec::new()),
+                    })
+                }
+            }
+        }
+        WsCommand::GetLatency => {
+            // clone the config, so that it isn't locked while calculating
+            let active_config = shared_data_inst.active_config.lock().unwrap().clone();
+            match active_config {
+                Some(conf) => Some(WsReply::GetLatency {
+                    result: WsResult::Ok,
+                    value: latency::calculate_latency(&conf),
+                }),
+                None => Some(WsReply::GetLatency {
+                    result: WsResult::Error,
+                    value: latency::Latency::default(),
+                }),
+            }
+        }
+        WsCommand::GetProcessingLoad => {
+            let stat = shared_data_inst.status.read().unwrap();
+            Some(WsReply::GetProcessingLoad {
+                result: WsResult::Ok,
+                value: stat.processing_load.clone(),
+            })
+        }
+        WsCommand::GetCaptureSpectrum => {
+            let spectrum = shared_data_inst
+                .status
+                .read()
+                .unwrap()
+                .capture_spectrum
+                .clone();
+            Some(WsReply::GetCaptureSpectrum {
+                result: spectrum_result(&spectrum),
+                value: spectrum,
+            })
+        }
+        WsCommand::GetPlaybackSpectrum => {
+            let spectrum = shared_data_inst
+                .status
+                .read()
+                .unwrap()
+                .playback_spectrum
+                .clone();
+            Some(WsReply::GetPlaybackSpectrum {
+                result: spectrum_result(&spectrum),
+                value: spectrum,
+            })
+        }
+        WsCommand::GetCaptureLoudness => {
+            let capstat = shared_data_inst.capture_status.read().unwrap();
+            Some(WsReply::GetCaptureLoudness {
+                result: WsResult::Ok,
+                value: capstat.loudness.clone(),
+            })
+        }
+        WsCommand::GetPlaybackLoudness => {
+            let pbstat = shared_data_inst.playback_status.read().unwrap();
+            Some(WsReply::GetPlaybackLoudness {
+                result: WsResult::Ok,
+                value: pbstat.loudness.clone(),
+            })
+        }
+        WsCommand::ResetLoudness => {
+            shared_data_inst.status.write().unwrap().reset_loudness = true;
+            Some(WsReply::ResetLoudness {
+                result: WsResult::Ok,
+            })
+        }
         WsCommand::None => None,
     }
 }
 
+/// Apply a patch to a config. A list is applied as a JSON Patch (RFC 6902),
+/// and anything else as a JSON Merge Patch (RFC 7386).
+/// The patched config is validated before it is returned.
+fn patch_config(
+    conf: &config::Configuration,
+    patch: &serde_json::Value,
+) -> Res<config::Configuration> {
+    let mut value = serde_json::to_value(conf)?;
+    if patch.is_array() {
+        let operations = json_patch::from_value(patch.clone())?;
+        json_patch::patch(&mut value, &operations)?;
+    } else {
+        json_patch::merge(&mut value, patch);
+    }
+    let mut patched = serde_json::from_value::<config::Configuration>(value)?;
+    config::validate_config(&mut patched, None)?;
+    Ok(patched)
+}
+
+/// Patch the config that is waiting to be applied, or the active config if there is none waiting,
+/// and pass the result on to be applied. Holding the lock of the new config while patching means
+/// that patches from several clients are applied one after the other, without overwriting each other.
+fn patch_pending_config(shared_data: &SharedData, patch: &serde_json::Value) -> Res<()> {
+    let mut new_config = shared_data.new_config.lock().unwrap();
+    let active_config = shared_data.active_config.lock().unwrap().clone();
+    let current = match (new_config.as_ref(), active_config.as_ref()) {
+        (Some(conf), _) | (None, Some(conf)) => conf,
+        (None, None) => return Err(config::ConfigError::new("No active config").into()),
+    };
+    let patched = patch_config(current, patch)?;
+    if let Some(active) = active_config.as_ref() {
+        let change = config::config_diff(active, &patched);
+        debug!("Patched config gives change: {:?}", change);
+        if let config::ConfigChange::None = change {
+            if new_config.is_none() {
+                return Ok(());
+            }
+        }
+    }
+    *new_config = Some(patched);
+    shared_data.signal_reload.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Replace the parameters of a single filter of the active config. The filter must already exist,
+/// and keep its type, so that the change is sent directly to the running pipeline,
+/// which updates the parameters of the filter without reloading the config.
+fn set_filter(shared_data: &SharedData, name: &str, filter: config::Filter) -> Res<()> {
+    if shared_data.pipeline_updates.lock().unwrap().is_none() {
+        return Err(config::ConfigError::new("Processing is not running").into());
+    }
+    let updated = {
+        let mut active_config = shared_data.active_config.lock().unwrap();
+        let mut conf = match active_config.clone() {
+            Some(conf) => conf,
+            None => return Err(config::ConfigError::new("No active config").into()),
+        };
+        match conf.filters.get(name) {
+            Some(current) => {
+                if mem::discriminant(current) != mem::discriminant(&filter) {
+                    let msg = format!("Filter '{}' can't be changed to a different type", name);
+                    return Err(config::ConfigError::new(&msg).into());
+                }
+                if *current == filter {
+                    return Ok(());
+                }
+            }
+            None => {
+                let msg = format!("No filter named '{}' in the active config", name);
+                return Err(config::ConfigError::new(&msg).into());
+            }
+        }
+        filters::validate_filter(conf.devices.samplerate, &filter)?;
+        let current = conf.filters.insert(name.to_string(), filter.clone());
+        if let (
+            Some(config::Filter::Conv {
+                partitioning: current_partitioning,
+                ..
+            }),
+            config::Filter::Conv { partitioning, .. },
+        ) = (current, &filter)
+        {
+            if current_partitioning != *partitioning {
+                let msg = format!(
+                    "Changing the partitioning of filter '{}' requires reloading the config",
+                    name
+                );
+                return Err(config::ConfigError::new(&msg).into());
+            }
+        }
+        if let config::Filter::Volume { .. } = filter {
+            basicfilters::validate_fader_limits(&conf)?;
+        }
+        *active_config = Some(conf.clone());
+        conf
+    };
+    // Send the change after releasing the lock, to not block reloads and other clients
+    let change = config::ConfigChange::FilterParameters {
+        filters: vec![name.to_string()],
+        mixers: Vec::new(),
+        processors: Vec::new(),
+    };
+    let sent = match shared_data.pipeline_updates.lock().unwrap().as_ref() {
+        Some(sender) => sender.send((change, updated)).is_ok(),
+        None => false,
+    };
+    if !sent {
+        return Err(config::ConfigError::new("Processing is not running").into());
+    }
+    debug!("Sent new parameters of filter '{}' to pipeline", name);
+    // Keep a config waiting to be applied in sync, so that it doesn't revert the change
+    if let Some(pending) = shared_data.new_config.lock().unwrap().as_mut() {
+        if let Some(current) = pending.filters.get_mut(name) {
+            if mem::discriminant(current) == mem::discriminant(&filter) {
+                *current = filter;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get the allowed volume range of a fader, given by the Volume filters of the active config.
+/// The range is unlimited when there is no active config, or no Volume filter uses the fader.
+fn fader_limits(shared_data: &SharedData, fader: &str) -> (f32, f32) {
+    shared_data
+        .active_config
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|conf| basicfilters::volume_limits(conf, fader))
+        .unwrap_or((f32::NEG_INFINITY, f32::INFINITY))
+}
+
+/// Export the response of the active config to the export directory, returns the base name of the written files.
+fn export_response(shared_data: &SharedData, name: &str) -> Res<String> {
+    let directory = match &shared_data.export_dir {
+        Some(directory) => directory,
+        None => {
+            return Err(config::ConfigError::new(
+                "Exporting is disabled, start with --export-dir to enable it",
+            )
+            .into())
+        }
+    };
+    let basename = response::export_basename(directory, name)?;
+    let active_config = shared_data.active_config.lock().unwrap().clone();
+    match active_config {
+        Some(conf) => {
+            response::export_response(&conf, &basename, response::DEFAULT_RESPONSE_LENGTH)?;
+            Ok(basename)
+        }
+        None => Err(config::ConfigError::new("No active config").into()),
+    }
+}
+
+/// A spectrum is only available while processing with the analyzer enabled.
+fn spectrum_result(spectrum: &Spectrum) -> WsResult {
+    if spectrum.frequencies.is_empty() {
+        WsResult::Error
+    } else {
+        WsResult::Ok
+    }
+}
+
+/// Get the magnitude in dB and phase in degrees of a filter in the active config.
+fn filter_frequency_response(
+    shared_data: &SharedData,
+    name: &str,
+    frequencies: &[f32],
+) -> Res<(Vec<f32>, Vec<f32>)> {
+    // copy what is needed, so that the config isn't locked while calculating
+    let (filter, samplerate) = {
+        let active_config = shared_data.active_config.lock().unwrap();
+        let conf = match active_config.as_ref() {
+            Some(conf) => conf,
+            None => return Err(config::ConfigError::new("No active config").into()),
+        };
+        match conf.filters.get(name) {
+            Some(filter) => (filter.clone(), conf.devices.samplerate),
+            None => {
+                let msg = format!("No filter named '{}' in the active config", name);
+                return Err(config::ConfigError::new(&msg).into());
+            }
+        }
+    };
+    let frequencies = frequencies
+        .iter()
+        .map(|f| *f as PrcFmt)
+        .collect::<Vec<PrcFmt>>();
+    let response = response::filter_frequency_response(&filter, samplerate, &frequencies)?;
+    let magnitude = response
+        .iter()
+        .map(|value| response::magnitude_db(value) as f32)
+        .collect();
+    let phase = response
+        .iter()
+        .map(|value| value.arg().to_degrees() as f32)
+        .collect();
+    Ok((magnitude, phase))
+}
+
 #[cfg(test)]
 mod tests {
-    use socketserver::{parse_command, WsCommand};
+    use config::{ConfigChange, Configuration, DelayParameters, Filter, GainParameters, TimeUnit};
+    use loudnessmeter::LoudnessLevels;
+    use socketserver::{
+        parse_command, patch_config, set_filter, SharedData, Subscription, WsCommand, WsEvent,
+        WsEventType,
+    };
+    use spectrum::Spectrum;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex, RwLock};
+    use std::time::Duration;
     use tungstenite::Message;
+    use PrcFmt;
+    use {
+        CaptureStatus, PlaybackStatus, ProcessingLoad, ProcessingParameters, ProcessingState,
+        ProcessingStatus, StopReason,
+    };
+
+    fn shared_data() -> SharedData {
+        SharedData {
+            signal_reload: Arc::new(AtomicBool::new(false)),
+            signal_exit: Arc::new(AtomicUsize::new(0)),
+            active_config: Arc::new(Mutex::new(None)),
+            active_config_path: Arc::new(Mutex::new(None)),
+            new_config: Arc::new(Mutex::new(None)),
+            previous_config: Arc::new(Mutex::new(None)),
+            pipeline_updates: Arc::new(Mutex::new(None)),
+            capture_status: Arc::new(RwLock::new(CaptureStatus {
+                measured_samplerate: 0,
+                update_interval: 1000,
+                signal_range: 0.0,
+                rate_adjust: 0.0,
+                state: ProcessingState::Inactive,
+                signal_rms: Vec::new(),
+                signal_peak: Vec::new(),
+                used_channels: Vec::new(),
+                loudness: LoudnessLevels::default(),
+            })),
+            playback_status: Arc::new(RwLock::new(PlaybackStatus {
+                buffer_level: 0,
+                clipped_samples: 0,
+                update_interval: 1000,
+                signal_rms: Vec::new(),
+                signal_peak: Vec::new(),
+                loudness: LoudnessLevels::default(),
+            })),
+            processing_status: Arc::new(RwLock::new(ProcessingParameters {
+                volume: 0.0,
+                mute: false,
+                faders: HashMap::new(),
+            })),
+            status: Arc::new(RwLock::new(ProcessingStatus {
+                stop_reason: StopReason::None,
+                processing_load: ProcessingLoad::default(),
+                capture_spectrum: Spectrum::default(),
+                playback_spectrum: Spectrum::default(),
+                reset_loudness: false,
+            })),
+            export_dir: None,
+        }
+    }
 
     #[test]
     fn parse_commands() {
@@ -690,4 +1461,142 @@ mod tests {
         let res = parse_command(cmd).unwrap();
         assert_eq!(res, WsCommand::SetConfigName("somefile".to_string()));
     }
+
+    #[test]
+    fn parse_subscribe() {
+        let cmd = Message::text("{\"Subscribe\": {\"events\": [\"State\", \"Volume\"]}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::Subscribe {
+                events: vec![WsEventType::State, WsEventType::Volume],
+                interval: 1000
+            }
+        );
+    }
+
+    #[test]
+    fn push_changes() {
+        let shared = shared_data();
+        let mut sub = Subscription::new(
+            vec![WsEventType::State, WsEventType::StopReason],
+            Duration::from_millis(0),
+        );
+        // the current values are pushed at the start
+        assert_eq!(
+            sub.poll(&shared),
+            vec![
+                WsEvent::State(ProcessingState::Inactive),
+                WsEvent::StopReason(StopReason::None)
+            ]
+        );
+        assert!(sub.poll(&shared).is_empty());
+        shared.capture_status.write().unwrap().state = ProcessingState::Running;
+        assert_eq!(
+            sub.poll(&shared),
+            vec![WsEvent::State(ProcessingState::Running)]
+        );
+        assert!(sub.poll(&shared).is_empty());
+    }
+
+    fn gain_config() -> Configuration {
+        let yaml = r#"
+devices:
+  samplerate: 48000
+  chunksize: 1024
+  capture:
+    type: Stdin
+    channels: 2
+    format: FLOAT64LE
+  playback:
+    type: Stdout
+    channels: 2
+    format: FLOAT64LE
+filters:
+  gain:
+    type: Gain
+    parameters:
+      gain: -3.0
+pipeline:
+  - type: Filter
+    channel: 0
+    names: [gain]
+"#;
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn get_gain(conf: &Configuration) -> PrcFmt {
+        match &conf.filters["gain"] {
+            Filter::Gain { parameters } => parameters.gain,
+            _ => panic!("wrong filter type"),
+        }
+    }
+
+    #[test]
+    fn patch_filter_gain() {
+        let conf = gain_config();
+        let patch = serde_json::json!([
+            {"op": "replace", "path": "/filters/gain/parameters/gain", "value": -6.0}
+        ]);
+        let patched = patch_config(&conf, &patch).unwrap();
+        assert_eq!(get_gain(&patched), -6.0);
+        let patch = serde_json::json!({"filters": {"gain": {"parameters": {"gain": 2.0}}}});
+        let patched = patch_config(&conf, &patch).unwrap();
+        assert_eq!(get_gain(&patched), 2.0);
+        // a missing filter, and a gain that doesn't pass validation
+        let patch = serde_json::json!([
+            {"op": "replace", "path": "/filters/other/parameters/gain", "value": -6.0}
+        ]);
+        assert!(patch_config(&conf, &patch).is_err());
+        let patch = serde_json::json!({"filters": {"gain": {"parameters": {"gain": 200.0}}}});
+        assert!(patch_config(&conf, &patch).is_err());
+    }
+
+    #[test]
+    fn set_single_filter() {
+        let shared = shared_data();
+        let filter = Filter::Gain {
+            parameters: GainParameters {
+                gain: -6.0,
+                inverted: false,
+                mute: false,
+            },
+        };
+        assert!(set_filter(&shared, "gain", filter.clone()).is_err());
+        *shared.active_config.lock().unwrap() = Some(gain_config());
+        // fails when processing isn't running
+        assert!(set_filter(&shared, "gain", filter.clone()).is_err());
+        let (tx, rx) = mpsc::channel();
+        *shared.pipeline_updates.lock().unwrap() = Some(tx);
+        set_filter(&shared, "gain", filter.clone()).unwrap();
+        let (change, conf) = rx.try_recv().unwrap();
+        match change {
+            ConfigChange::FilterParameters {
+                filters,
+                mixers,
+                processors,
+            } => {
+                assert_eq!(filters, vec!["gain".to_string()]);
+                assert!(mixers.is_empty() && processors.is_empty());
+            }
+            _ => panic!("wrong kind of change"),
+        }
+        assert_eq!(get_gain(&conf), -6.0);
+        assert_eq!(
+            get_gain(shared.active_config.lock().unwrap().as_ref().unwrap()),
+            -6.0
+        );
+        assert!(shared.new_config.lock().unwrap().is_none());
+        assert!(!shared.signal_reload.load(Ordering::Relaxed));
+        // unknown names and changes of filter type are rejected
+        assert!(set_filter(&shared, "other", filter).is_err());
+        let delay = Filter::Delay {
+            parameters: DelayParameters {
+                delay: 1.0,
+                unit: TimeUnit::Milliseconds,
+                subsample: false,
+            },
+        };
+        assert!(set_filter(&shared, "gain", delay).is_err());
+    }
 }
@@ -0,0 +1,284 @@
+use audiodevice::AudioChunk;
+use config;
+use num_complex::Complex;
+use realfft::{RealFftPlanner, RealToComplex};
+use serde::Serialize;
+use std::sync::Arc;
+
+use PrcFmt;
+use Res;
+
+/// Levels in dB of a set of frequency bands, for each channel of a signal.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Spectrum {
+    /// Center frequencies of the bands.
+    pub frequencies: Vec<f32>,
+    /// Levels of each band, one list per channel.
+    pub levels: Vec<Vec<f32>>,
+}
+
+/// A frequency band, covering the FFT bins from `first_bin` up to but not including `last_bin`.
+struct Band {
+    first_bin: usize,
+    last_bin: usize,
+}
+
+/// Get the center frequencies of the bands from the one nearest to `min_freq` to the one nearest to `max_freq`,
+/// with `bands_per_octave` bands per octave, aligned so that one band is centered at 1 kHz.
+pub fn band_frequencies(bands_per_octave: usize, min_freq: f32, max_freq: f32) -> Vec<f32> {
+    let bpo = bands_per_octave as f32;
+    let first = (bpo * (min_freq / 1000.0).log2()).round() as i32;
+    let last = (bpo * (max_freq / 1000.0).log2()).round() as i32;
+    (first..=last)
+        .map(|n| 1000.0 * (n as f32 / bpo).exp2())
+        .collect()
+}
+
+/// A spectrum analyzer, that calculates the smoothed spectrum of the latest `fft_size` samples
+/// of each channel, every time half of that number of new samples have arrived.
+pub struct SpectrumAnalyzer {
+    fft_size: usize,
+    samplerate: usize,
+    averaging_time: PrcFmt,
+    window: Vec<PrcFmt>,
+    fft: Arc<dyn RealToComplex<PrcFmt>>,
+    scratch: Vec<Complex<PrcFmt>>,
+    fft_input: Vec<PrcFmt>,
+    fft_output: Vec<Complex<PrcFmt>>,
+    buffers: Vec<Vec<PrcFmt>>,
+    new_samples: usize,
+    frequencies: Vec<f32>,
+    bands: Vec<Band>,
+    band_power: Vec<Vec<PrcFmt>>,
+    scale: PrcFmt,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(conf: &config::SpectrumParameters, samplerate: usize) -> Self {
+        let fft_size = conf.fft_size;
+        let pi = std::f64::consts::PI as PrcFmt;
+        // Hann window
+        let window = (0..fft_size)
+            .map(|n| 0.5 - 0.5 * (2.0 * pi * n as PrcFmt / fft_size as PrcFmt).cos())
+            .collect::<Vec<PrcFmt>>();
+        let window_power = window.iter().map(|w| w * w).sum::<PrcFmt>() / fft_size as PrcFmt;
+        let mut planner = RealFftPlanner::<PrcFmt>::new();
+        let fft = planner.plan_fft_forward(fft_size);
+        let scratch = fft.make_scratch_vec();
+        let fft_input = fft.make_input_vec();
+        let fft_output = fft.make_output_vec();
+
+        let nyquist = samplerate as f32 / 2.0;
+        let max_freq = conf
+            .max_freq
+            .min(nyquist / 2.0f32.powf(0.5 / conf.bands_per_octave as f32));
+        let frequencies = band_frequencies(conf.bands_per_octave, conf.min_freq, max_freq);
+        let bin_width = samplerate as f32 / fft_size as f32;
+        let half_band = 2.0f32.powf(0.5 / conf.bands_per_octave as f32);
+        let bands = frequencies
+            .iter()
+            .map(|freq| {
+                let first_bin = ((freq / half_band) / bin_width).ceil() as usize;
+                let last_bin =
+                    (((freq * half_band) / bin_width).ceil() as usize).min(fft_size / 2 + 1);
+                if last_bin > first_bin {
+                    Band {
+                        first_bin,
+                        last_bin,
+                    }
+                } else {
+                    // Band is narrower than the FFT resolution, use the nearest bin
+                    let nearest = ((freq / bin_width).round() as usize).min(fft_size / 2);
+                    Band {
+                        first_bin: nearest,
+                        last_bin: nearest + 1,
+                    }
+                }
+            })
+            .collect();
+        debug!(
+            "Spectrum analyzer with {} bands from {} to {} Hz, fft size {}",
+            frequencies.len(),
+            frequencies.first().unwrap_or(&0.0),
+            frequencies.last().unwrap_or(&0.0),
+            fft_size
+        );
+        SpectrumAnalyzer {
+            fft_size,
+            samplerate,
+            averaging_time: conf.averaging_time as PrcFmt,
+            window,
+            fft,
+            scratch,
+            fft_input,
+            fft_output,
+            buffers: Vec::new(),
+            new_samples: 0,
+            frequencies,
+            bands,
+            band_power: Vec::new(),
+            // Scale the one-sided power spectrum so that the sum over all bins gives the mean square of the signal
+            scale: 2.0 / (fft_size as PrcFmt * fft_size as PrcFmt * window_power),
+        }
+    }
+
+    /// Add the valid frames of a chunk, and update the spectrum when enough new samples have arrived.
+    /// Returns true if the spectrum was updated.
+    pub fn process_chunk(&mut self, chunk: &AudioChunk) -> bool {
+        let channels = chunk.waveforms.len();
+        if self.buffers.len() != channels {
+            self.buffers = vec![vec![0.0; self.fft_size]; channels];
+            self.band_power = vec![vec![0.0; self.bands.len()]; channels];
+            self.new_samples = 0;
+        }
+        let frames = chunk.valid_frames;
+        for (buffer, waveform) in self.buffers.iter_mut().zip(chunk.waveforms.iter()) {
+            // Channels that are not in use may have empty waveforms, treat these as silence
+            let values = if waveform.len() >= frames {
+                &waveform[0..frames]
+            } else {
+                &[]
+            };
+            let nbr = frames.min(self.fft_size);
+            buffer.rotate_left(nbr);
+            if values.is_empty() {
+                for value in buffer[self.fft_size - nbr..].iter_mut() {
+                    *value = 0.0;
+                }
+            } else {
+                buffer[self.fft_size - nbr..].copy_from_slice(&values[frames - nbr..]);
+            }
+        }
+        self.new_samples += frames;
+        if self.new_samples < self.fft_size / 2 {
+            return false;
+        }
+        let smoothing = if self.averaging_time > 0.0 {
+            (-(self.new_samples as PrcFmt) / (self.averaging_time * self.samplerate as PrcFmt))
+                .exp()
+        } else {
+            0.0
+        };
+        self.new_samples = 0;
+        for (buffer, powers) in self.buffers.iter().zip(self.band_power.iter_mut()) {
+            for ((input, value), window) in self
+                .fft_input
+                .iter_mut()
+                .zip(buffer.iter())
+                .zip(self.window.iter())
+            {
+                *input = value * window;
+            }
+            self.fft
+                .process_with_scratch(&mut self.fft_input, &mut self.fft_output, &mut self.scratch)
+                .unwrap();
+            for (band, power) in self.bands.iter().zip(powers.iter_mut()) {
+                let new_power = self.fft_output[band.first_bin..band.last_bin]
+                    .iter()
+                    .map(|value| value.norm_sqr())
+                    .sum::<PrcFmt>()
+                    * self.scale;
+                *power = smoothing * *power + (1.0 - smoothing) * new_power;
+            }
+        }
+        true
+    }
+
+    /// Get the current spectrum, with levels in dB relative to the mean square of a full scale signal.
+    pub fn get_spectrum(&self) -> Spectrum {
+        let levels = self
+            .band_power
+            .iter()
+            .map(|powers| {
+                powers
+                    .iter()
+                    .map(|power| (10.0 * power.max(1.0e-20).log10()) as f32)
+                    .collect()
+            })
+            .collect();
+        Spectrum {
+            frequencies: self.frequencies.clone(),
+            levels,
+        }
+    }
+}
+
+/// Validate the spectrum analyzer config.
+pub fn validate_config(conf: &config::SpectrumParameters, samplerate: usize) -> Res<()> {
+    if conf.bands_per_octave == 0 {
+        return Err(
+            config::ConfigError::new("Spectrum bands_per_octave must be at least 1").into(),
+        );
+    }
+    if conf.fft_size < 64 || conf.fft_size % 2 != 0 {
+        return Err(config::ConfigError::new(
+            "Spectrum fft_size must be an even number of at least 64",
+        )
+        .into());
+    }
+    if conf.min_freq <= 0.0 || conf.min_freq >= conf.max_freq {
+        return Err(config::ConfigError::new(
+            "Spectrum min_freq must be positive and lower than max_freq",
+        )
+        .into());
+    }
+    if conf.min_freq >= samplerate as f32 / 2.0 {
+        return Err(
+            config::ConfigError::new("Spectrum min_freq must be lower than samplerate/2").into(),
+        );
+    }
+    if conf.averaging_time < 0.0 {
+        return Err(config::ConfigError::new("Spectrum averaging_time cannot be negative").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use audiodevice::AudioChunk;
+    use config::SpectrumParameters;
+    use spectrum::{band_frequencies, SpectrumAnalyzer};
+    use PrcFmt;
+
+    fn is_close(left: f32, right: f32, maxdiff: f32) -> bool {
+        println!("{} - {}", left, right);
+        (left - right).abs() < maxdiff
+    }
+
+    #[test]
+    fn third_octave_bands() {
+        let freqs = band_frequencies(3, 20.0, 20000.0);
+        assert_eq!(freqs.len(), 31);
+        assert!(is_close(freqs[0], 19.686, 0.01));
+        assert!(is_close(freqs[17], 1000.0, 0.01));
+        assert!(is_close(freqs[30], 20158.7, 0.1));
+    }
+
+    #[test]
+    fn sine_in_one_band() {
+        let conf = SpectrumParameters {
+            bands_per_octave: 1,
+            min_freq: 100.0,
+            max_freq: 10000.0,
+            fft_size: 4096,
+            averaging_time: 0.0,
+            capture: true,
+            playback: true,
+        };
+        let mut analyzer = SpectrumAnalyzer::new(&conf, 48000);
+        let pi = std::f64::consts::PI as PrcFmt;
+        let waveform = (0..4096)
+            .map(|n| 0.5 * (2.0 * pi * 1000.0 * n as PrcFmt / 48000.0).sin())
+            .collect::<Vec<PrcFmt>>();
+        let chunk = AudioChunk::new(vec![waveform, vec![]], 0.5, -0.5, 4096, 4096);
+        assert!(analyzer.process_chunk(&chunk));
+        let spectrum = analyzer.get_spectrum();
+        assert_eq!(spectrum.frequencies.len(), 7);
+        // the 1 kHz band gets the mean square of the sine, 0.125 or -9.03 dB
+        assert!(is_close(spectrum.levels[0][3], -9.03, 0.05));
+        assert!(spectrum.levels[0][1] < -60.0);
+        assert!(spectrum.levels[0][5] < -60.0);
+        // the unused second channel is silent
+        assert!(spectrum.levels[1].iter().all(|level| *level < -150.0));
+    }
+}
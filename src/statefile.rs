@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use Fader;
+use ProcessingParameters;
+use Res;
+
+/// How often the state is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The state is written when it has been unchanged for this long,
+/// to avoid writing the file repeatedly while the volume is being adjusted.
+const WRITE_DELAY: Duration = Duration::from_millis(1000);
+
+/// The settings that are kept across restarts.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub config_path: Option<String>,
+    #[serde(default)]
+    pub volume: f32,
+    #[serde(default)]
+    pub mute: bool,
+    #[serde(default)]
+    pub faders: HashMap<String, Fader>,
+}
+
+impl State {
+    /// Get the current state of the faders and the active config path.
+    pub fn current(params: &ProcessingParameters, config_path: &Option<String>) -> Self {
+        State {
+            config_path: config_path.clone(),
+            volume: params.volume,
+            mute: params.mute,
+            faders: params.faders.clone(),
+        }
+    }
+}
+
+/// Read a state file, returns None if the file is missing or can't be parsed.
+pub fn load_state(filename: &str) -> Option<State> {
+    if !Path::new(filename).exists() {
+        info!("State file '{}' doesn't exist, using defaults", filename);
+        return None;
+    }
+    let contents = match fs::read_to_string(filename) {
+        Ok(contents) => contents,
+        Err(err) => {
+            warn!("Unable to read state file '{}', error: {}", filename, err);
+            return None;
+        }
+    };
+    match serde_yaml::from_str::<State>(&contents) {
+        Ok(state) => {
+            debug!("Loaded state {:?}", state);
+            Some(state)
+        }
+        Err(err) => {
+            warn!("Invalid state file '{}', error: {}", filename, err);
+            None
+        }
+    }
+}
+
+/// Write a state file. The state is first written and synced to a temporary file that then replaces the old one,
+/// and the directory is synced to make the rename durable,
+/// so that a power loss while writing doesn't leave a broken file behind.
+pub fn save_state(filename: &str, state: &State) -> Res<()> {
+    let contents = serde_yaml::to_string(state)?;
+    let tempname = format!("{}.tmp", filename);
+    {
+        let mut file = File::create(&tempname)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tempname, filename)?;
+    sync_parent_dir(filename)?;
+    Ok(())
+}
+
+/// Sync the directory containing a file, so that a rename of the file is persisted.
+#[cfg(unix)]
+fn sync_parent_dir(filename: &str) -> Res<()> {
+    let dir = match Path::new(filename).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories can't be opened for syncing on this platform, the rename is left to the file system.
+#[cfg(not(unix))]
+fn sync_parent_dir(_filename: &str) -> Res<()> {
+    Ok(())
+}
+
+/// Keeps track of changes, and decides when the state should be written.
+pub struct Debouncer {
+    saved: State,
+    pending: Option<(State, Instant)>,
+}
+
+impl Debouncer {
+    pub fn new(saved: State) -> Self {
+        Debouncer {
+            saved,
+            pending: None,
+        }
+    }
+
+    /// Update with the current state, returns the state to write once it has been unchanged for `WRITE_DELAY`.
+    pub fn update(&mut self, state: State, now: Instant) -> Option<State> {
+        if state == self.saved {
+            self.pending = None;
+            return None;
+        }
+        match &self.pending {
+            Some((pending, changed)) if *pending == state => {
+                if now.duration_since(*changed) >= WRITE_DELAY {
+                    self.saved = state.clone();
+                    self.pending = None;
+                    return Some(state);
+                }
+            }
+            _ => {
+                self.pending = Some((state, now));
+            }
+        }
+        None
+    }
+}
+
+/// Start a thread that writes the state file whenever the volume, mute or active config path changes.
+pub fn start_state_writer(
+    filename: String,
+    processing_params: Arc<RwLock<ProcessingParameters>>,
+    config_path: Arc<Mutex<Option<String>>>,
+) {
+    let initial = State::current(
+        &processing_params.read().unwrap(),
+        &config_path.lock().unwrap(),
+    );
+    if let Err(err) = save_state(&filename, &initial) {
+        warn!("Unable to write state file '{}', error: {}", filename, err);
+    }
+    thread::Builder::new()
+        .name("StateWriter".to_string())
+        .spawn(move || {
+            let mut debouncer = Debouncer::new(initial);
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                let state = State::current(
+                    &processing_params.read().unwrap(),
+                    &config_path.lock().unwrap(),
+                );
+                if let Some(state) = debouncer.update(state, Instant::now()) {
+                    debug!("Writing state file '{}'", filename);
+                    if let Err(err) = save_state(&filename, &state) {
+                        warn!("Unable to write state file '{}', error: {}", filename, err);
+                    }
+                }
+            }
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use statefile::{load_state, save_state, Debouncer, State, WRITE_DELAY};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn save_and_load() {
+        let filename = std::env::temp_dir()
+            .join("camilladsp_test_state.yml")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let mut state = State {
+            config_path: Some("/some/config.yml".to_string()),
+            volume: -23.5,
+            mute: true,
+            ..Default::default()
+        };
+        state.faders.insert("Sub".to_string(), Default::default());
+        save_state(&filename, &state).unwrap();
+        assert_eq!(load_state(&filename), Some(state));
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(load_state(&filename), None);
+    }
+
+    #[test]
+    fn debounce_writes() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(State::default());
+        assert_eq!(debouncer.update(State::default(), start), None);
+        let mut state = State {
+            volume: -10.0,
+            ..Default::default()
+        };
+        assert_eq!(debouncer.update(state.clone(), start), None);
+        // a new change restarts the delay
+        state.volume = -12.0;
+        let later = start + Duration::from_millis(500);
+        assert_eq!(debouncer.update(state.clone(), later), None);
+        assert_eq!(debouncer.update(state.clone(), start + WRITE_DELAY), None);
+        assert_eq!(
+            debouncer.update(state.clone(), later + WRITE_DELAY),
+            Some(state.clone())
+        );
+        // already written
+        assert_eq!(debouncer.update(state, later + 2 * WRITE_DELAY), None);
+    }
+}